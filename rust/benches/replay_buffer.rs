@@ -0,0 +1,91 @@
+//! Compares minibatch sampling from the flat `ReplayBuffer` against stacking one
+//! tensor per transition, which is how the buffer used to store states.
+
+use candle_core::{Device, Tensor};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use rand::Rng;
+use rust::{environment::ObsSpace, replay_buffer::ReplayBuffer};
+
+const CAPACITY: usize = 10000;
+const BATCH_SIZE: usize = 64;
+const ACTION_COUNT: usize = 4;
+const STATE_SHAPE: [usize; 3] = [6, 6, 6];
+
+fn random_state(rng: &mut impl Rng) -> Tensor {
+    let size = STATE_SHAPE.iter().product();
+    let data: Vec<f32> = (0..size)
+        .map(|_| if rng.gen_bool(0.2) { 1. } else { 0. })
+        .collect();
+    Tensor::from_vec(data, STATE_SHAPE.as_slice(), &Device::Cpu)
+        .unwrap()
+        .unsqueeze(0)
+        .unwrap()
+}
+
+fn filled_buffer(obs_space: &ObsSpace) -> ReplayBuffer {
+    let mut rng = rand::thread_rng();
+    let mut buffer = ReplayBuffer::new(obs_space, ACTION_COUNT, CAPACITY);
+    for _ in 0..CAPACITY {
+        buffer
+            .insert_step(
+                random_state(&mut rng),
+                random_state(&mut rng),
+                Tensor::new(&[rng.gen_range(0..ACTION_COUNT as u32)], &Device::Cpu).unwrap(),
+                &[rng.gen()],
+                &[false],
+                &[false],
+                Tensor::zeros((1, ACTION_COUNT), candle_core::DType::F32, &Device::Cpu).unwrap(),
+            )
+            .unwrap();
+    }
+    buffer
+}
+
+fn sample(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sample");
+
+    let mut rng = rand::thread_rng();
+    let buffer = filled_buffer(&ObsSpace::MultiBinary(STATE_SHAPE.to_vec()));
+    group.bench_function("flat_bits", |b| {
+        b.iter(|| buffer.sample(BATCH_SIZE, 1., &mut rng).unwrap())
+    });
+
+    let buffer = filled_buffer(&ObsSpace::Box {
+        low: vec![0.; STATE_SHAPE.iter().product()],
+        high: vec![1.; STATE_SHAPE.iter().product()],
+    });
+    group.bench_function("flat_floats", |b| {
+        b.iter(|| buffer.sample(BATCH_SIZE, 1., &mut rng).unwrap())
+    });
+
+    // One tensor per transition, stacked on every batch.
+    let states: Vec<_> = (0..CAPACITY)
+        .map(|_| random_state(&mut rng).squeeze(0).unwrap())
+        .collect();
+    let next_states: Vec<_> = (0..CAPACITY)
+        .map(|_| random_state(&mut rng).squeeze(0).unwrap())
+        .collect();
+    group.bench_function("stacked_tensors", |b| {
+        b.iter_batched(
+            || {
+                (0..BATCH_SIZE)
+                    .map(|_| rng.gen_range(0..CAPACITY))
+                    .collect::<Vec<_>>()
+            },
+            |indices| {
+                let states: Vec<_> = indices.iter().map(|&i| &states[i]).collect();
+                let next_states: Vec<_> = indices.iter().map(|&i| &next_states[i]).collect();
+                (
+                    Tensor::stack(&states, 0).unwrap(),
+                    Tensor::stack(&next_states, 0).unwrap(),
+                )
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, sample);
+criterion_main!(benches);
@@ -0,0 +1,57 @@
+//! Scores a saved model over many episodes and prints statistics.
+//!
+//! Usage: `eval [grid|cartpole] [level_dir] [--model=path] [--episodes=n] [--max-steps=n]
+//! [--seed=n]`. Without a level directory, grid levels are generated.
+
+use anyhow::{bail, Result};
+use rust::{
+    cartpole::CartpoleEnv,
+    cli::{model_path, RunArgs},
+    env::GridEnv,
+    evaluate::{evaluate, GreedyPolicy},
+    level::LevelSource,
+    saved_model::{load_model, ModelMeta},
+};
+
+fn main() -> Result<()> {
+    let RunArgs {
+        args,
+        model,
+        episodes,
+        max_steps,
+        seed,
+    } = RunArgs::parse(std::env::args().skip(1), 100, 500, |_, _| Ok(false))?;
+    let mode = args.first().cloned().unwrap_or("grid".into());
+    let model = model.unwrap_or(model_path(&mode));
+
+    let report = match mode.as_str() {
+        "grid" => {
+            let levels = LevelSource::load_or_generate(args.get(1), Some(seed))?;
+            let mut env = GridEnv::with_levels(levels);
+            let (net, meta) = load_model(&model, &env)?;
+            print_meta(&meta);
+            evaluate(&mut GreedyPolicy::new(&*net), &mut env, episodes, max_steps)?
+        }
+        "cartpole" => {
+            let mut env = CartpoleEnv::with_seed(Some(seed));
+            let (net, meta) = load_model(&model, &env)?;
+            print_meta(&meta);
+            evaluate(&mut GreedyPolicy::new(&*net), &mut env, episodes, max_steps)?
+        }
+        _ => bail!("Unknown mode `{mode}`, expected `grid` or `cartpole`."),
+    };
+    println!("{report}");
+    Ok(())
+}
+
+fn print_meta(meta: &ModelMeta) {
+    println!(
+        "Model: {:?} predicting {:?}, trained for {} iterations on {} v{} (config {})",
+        meta.architecture,
+        meta.distribution,
+        meta.step,
+        meta.env_name,
+        meta.env_version,
+        meta.config_hash
+    );
+}
@@ -0,0 +1,64 @@
+//! Watches a saved grid model play, rendering each step in the terminal.
+//!
+//! Usage: `play [level_dir] [--model=path] [--episodes=n] [--max-steps=n] [--delay-ms=n]
+//! [--seed=n]`. Without a level directory, levels are generated.
+
+use std::{thread, time::Duration};
+
+use anyhow::Result;
+use rust::{
+    cli::{model_path, parse_value, RunArgs},
+    env::GridEnv,
+    evaluate::{evaluate_with, GreedyPolicy},
+    level::LevelSource,
+    saved_model::load_model,
+};
+
+fn main() -> Result<()> {
+    let mut delay_ms = 300;
+    let RunArgs {
+        args,
+        model,
+        episodes,
+        max_steps,
+        seed,
+    } = RunArgs::parse(std::env::args().skip(1), 5, 100, |key, value| {
+        if key != "delay-ms" {
+            return Ok(false);
+        }
+        delay_ms = parse_value(key, value)?;
+        Ok(true)
+    })?;
+    let model = model.unwrap_or(model_path("grid"));
+
+    let levels = LevelSource::load_or_generate(args.first(), Some(seed))?;
+    let mut env = GridEnv::with_levels(levels);
+    let (net, _) = load_model(&model, &env)?;
+    let delay = Duration::from_millis(delay_ms);
+    let report = evaluate_with(
+        &mut GreedyPolicy::new(&*net),
+        &mut env,
+        episodes,
+        max_steps,
+        |env, progress| {
+            // Clear the terminal and move the cursor to the top left.
+            print!("\x1b[2J\x1b[H");
+            println!(
+                "Episode {}/{episodes}, step {}, return {:.3}",
+                progress.episode + 1,
+                progress.step,
+                progress.episode_return
+            );
+            env.render();
+            if let Some(outcome) = progress.outcome {
+                println!("{outcome:?}");
+                thread::sleep(delay * 3);
+            } else {
+                thread::sleep(delay);
+            }
+            Ok(())
+        },
+    )?;
+    println!("{report}");
+    Ok(())
+}
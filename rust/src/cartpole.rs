@@ -1,4 +1,9 @@
-use rand::Rng;
+use anyhow::Result;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::environment::{ActSpace, EnvState, Environment, ObsSpace, StepInfo};
 
 const GRAVITY: f32 = 9.8;
 const MASS_CART: f32 = 1.0;
@@ -10,26 +15,81 @@ const FORCE_MAG: f32 = 10.0;
 const TAU: f32 = 0.02;
 const THETA_THRESHOLD_RADIANS: f32 = 12.0 * 2.0 * std::f32::consts::PI / 360.0;
 const X_THRESHOLD: f32 = 2.4;
+/// Episodes are truncated after this many steps, matching `CartPole-v1`.
+const MAX_TIME: u32 = 500;
 
 /// Cart position, cart velocity, pole angle, pole angular velocity.
 pub type State = (f32, f32, f32, f32);
 
 /// Rust implementation of the cartpole environment.
 /// Based on the OpenAI gym implementation.
+#[derive(Serialize, Deserialize)]
 pub struct CartpoleEnv {
     pub state: State,
+    pub timer: u32,
+    /// Used to pick starting states.
+    rng: ChaCha12Rng,
 }
 
 impl CartpoleEnv {
     pub fn new() -> CartpoleEnv {
+        Self::with_seed(None)
+    }
+
+    /// Creates an environment whose starting states are drawn from `seed`.
+    /// If `None`, a random seed is used.
+    pub fn with_seed(seed: Option<u64>) -> CartpoleEnv {
+        let rng = match seed {
+            Some(seed) => ChaCha12Rng::seed_from_u64(seed),
+            None => ChaCha12Rng::from_entropy(),
+        };
         let mut env = CartpoleEnv {
             state: (0.0, 0.0, 0.0, 0.0),
+            timer: 0,
+            rng,
         };
-        env.reset();
+        env.start_episode();
         env
     }
 
-    pub fn step(&mut self, action: u32) -> (State, f32, bool) {
+    /// Puts the cart near the center with the pole close to upright.
+    fn start_episode(&mut self) {
+        let low = -0.05;
+        let high = 0.05;
+        self.timer = 0;
+        self.state = (
+            self.rng.gen_range(low..high),
+            self.rng.gen_range(low..high),
+            self.rng.gen_range(low..high),
+            self.rng.gen_range(low..high),
+        );
+    }
+}
+
+impl Environment for CartpoleEnv {
+    type Obs = State;
+
+    const NAME: &'static str = "cartpole";
+    const VERSION: u32 = 1;
+
+    fn observation_space(&self) -> ObsSpace {
+        let high = vec![
+            X_THRESHOLD * 2.0,
+            f32::MAX,
+            THETA_THRESHOLD_RADIANS * 2.0,
+            f32::MAX,
+        ];
+        ObsSpace::Box {
+            low: high.iter().map(|h| -h).collect(),
+            high,
+        }
+    }
+
+    fn action_space(&self) -> ActSpace {
+        ActSpace::Discrete(2)
+    }
+
+    fn step(&mut self, action: u32) -> (State, f32, StepInfo) {
         let (x, x_dot, theta, theta_dot) = self.state;
         let force = if action == 1 { FORCE_MAG } else { -FORCE_MAG };
         let cos_theta = theta.cos();
@@ -50,28 +110,42 @@ impl CartpoleEnv {
             || !(-THETA_THRESHOLD_RADIANS..=THETA_THRESHOLD_RADIANS).contains(&theta);
         let reward = 1.0;
 
-        (self.state, reward, terminated)
+        self.timer += 1;
+        let truncated = self.timer >= MAX_TIME;
+
+        (
+            self.state,
+            reward,
+            StepInfo {
+                terminated,
+                truncated,
+                action_mask: vec![false; 2],
+            },
+        )
     }
 
-    pub fn reset(&mut self) -> State {
-        let low = -0.05;
-        let high = 0.05;
-        let mut rng = rand::thread_rng();
-        self.state = (
-            rng.gen_range(low..high),
-            rng.gen_range(low..high),
-            rng.gen_range(low..high),
-            rng.gen_range(low..high),
-        );
-        self.state
+    fn reset(&mut self) -> Result<(State, StepInfo)> {
+        self.start_episode();
+        Ok((self.state, StepInfo::unmasked(2)))
+    }
+
+    /// The pole stayed up until the time limit.
+    fn is_success(&self) -> bool {
+        let (x, _, theta, _) = self.state;
+        self.timer >= MAX_TIME
+            && (-X_THRESHOLD..=X_THRESHOLD).contains(&x)
+            && (-THETA_THRESHOLD_RADIANS..=THETA_THRESHOLD_RADIANS).contains(&theta)
     }
+}
 
-    pub fn observation_space(&self) -> usize {
-        4
+impl EnvState for CartpoleEnv {
+    fn save_state(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
     }
 
-    pub fn action_space(&self) -> usize {
-        2
+    fn load_state(&mut self, state: &str) -> Result<()> {
+        *self = serde_json::from_str(state)?;
+        Ok(())
     }
 }
 
@@ -79,4 +153,4 @@ impl Default for CartpoleEnv {
     fn default() -> Self {
         Self::new()
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,344 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{bail, Context, Result};
+use candle_core::{Device, Tensor};
+use candle_nn::VarMap;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use safetensors::SafeTensors;
+
+use crate::{
+    config::TrainConfig, environment::EnvState, optim::AdamW, replay_buffer::ReplayBuffer,
+    saved_model::save_tensors,
+};
+
+/// Version of the format written by `Checkpoint::save`.
+const CHECKPOINT_FORMAT_VERSION: &str = "1";
+const STATE_FILE: &str = "checkpoint.safetensors";
+const BUFFER_PREFIX: &str = "replay_buffer_";
+const CONFIG_FILE: &str = "config.toml";
+
+/// Everything needed to resume a training run exactly where it stopped.
+///
+/// A checkpoint is a directory holding the networks, optimizer moments, iteration,
+/// RNG and environment state in `checkpoint.safetensors`, the replay buffer in
+/// `replay_buffer_<step>.safetensors` and the hyperparameters in `config.toml`. The
+/// epsilon and importance sampling schedules are derived from `step`, so they pick up
+/// where they left off.
+///
+/// Every file is written to a temporary file and renamed into place, and the state
+/// file goes last and names the buffer saved with it. A run killed while saving
+/// leaves the previous checkpoint intact.
+pub struct Checkpoint<'a> {
+    /// The next iteration to run.
+    pub step: usize,
+    /// Saved for reference, use `Checkpoint::load_config` to read it back.
+    pub config: &'a TrainConfig,
+    pub q_vars: &'a mut VarMap,
+    pub target_vars: &'a mut VarMap,
+    pub optimizer: &'a mut AdamW,
+    pub rng: &'a mut ChaCha8Rng,
+    pub buffer: &'a mut ReplayBuffer,
+    pub train_env: &'a mut dyn EnvState,
+    pub test_env: &'a mut dyn EnvState,
+    /// Observation of the training environment the next step acts on.
+    pub obs: &'a mut Tensor,
+    /// Action mask of `obs`.
+    pub mask: &'a mut Tensor,
+}
+
+/// Writes a file with `write` under a temporary name, then renames it to `path`.
+fn write_atomic(path: &Path, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let temp = path.with_extension("tmp");
+    write(&temp)?;
+    std::fs::rename(&temp, path)
+        .with_context(|| format!("Could not move {} into place", path.display()))
+}
+
+fn named_tensors(vm: &VarMap, prefix: &str) -> Vec<(String, Tensor)> {
+    vm.data()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, var)| (format!("{prefix}.{name}"), var.as_tensor().clone()))
+        .collect()
+}
+
+/// Restores every variable in `vm` from `tensors`, failing if any are missing.
+fn restore_vars(vm: &mut VarMap, tensors: &HashMap<String, Tensor>, prefix: &str) -> Result<()> {
+    let names: Vec<_> = vm.data().lock().unwrap().keys().cloned().collect();
+    for name in names {
+        let key = format!("{prefix}.{name}");
+        let tensor = tensors
+            .get(&key)
+            .with_context(|| format!("Checkpoint is missing `{key}`."))?;
+        vm.set_one(&name, tensor)
+            .with_context(|| format!("Could not restore `{key}`."))?;
+    }
+    Ok(())
+}
+
+impl Checkpoint<'_> {
+    /// Writes the checkpoint to `dir`, creating it if needed.
+    pub fn save(&self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create checkpoint directory {}", dir.display()))?;
+        let buffer_file = format!("{BUFFER_PREFIX}{}.safetensors", self.step);
+        write_atomic(&dir.join(&buffer_file), |path| self.buffer.save(path))?;
+        write_atomic(&dir.join(CONFIG_FILE), |path| self.config.save(path))?;
+
+        let mut tensors = named_tensors(self.q_vars, "q");
+        tensors.extend(named_tensors(self.target_vars, "target"));
+        tensors.extend(
+            self.optimizer
+                .state()
+                .into_iter()
+                .map(|(name, t)| (format!("opt.{name}"), t)),
+        );
+        tensors.push(("obs".to_string(), self.obs.clone()));
+        tensors.push(("mask".to_string(), self.mask.clone()));
+        let seed: String = self
+            .rng
+            .get_seed()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        let metadata: HashMap<_, _> = [
+            ("format_version", CHECKPOINT_FORMAT_VERSION.to_string()),
+            ("step", self.step.to_string()),
+            ("optimizer_step", self.optimizer.step_t.to_string()),
+            ("rng_seed", seed),
+            ("rng_stream", self.rng.get_stream().to_string()),
+            ("rng_word_pos", self.rng.get_word_pos().to_string()),
+            ("train_env", self.train_env.save_state()?),
+            ("test_env", self.test_env.save_state()?),
+            ("buffer_file", buffer_file.clone()),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+
+        let path = dir.join(STATE_FILE);
+        write_atomic(&path, |temp| save_tensors(tensors, metadata, temp))
+            .with_context(|| format!("Could not save checkpoint to {}", path.display()))?;
+
+        // Only the buffer named by the state file is needed now.
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default();
+            if name.starts_with(BUFFER_PREFIX) && name != buffer_file {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("Could not remove {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the config a checkpoint was trained with.
+    pub fn load_config(dir: impl AsRef<Path>) -> Result<TrainConfig> {
+        TrainConfig::load(dir.as_ref().join(CONFIG_FILE))
+    }
+
+    /// Loads the checkpoint in `dir` into the referenced state.
+    /// The networks and optimizer must have the same variables as when it was saved.
+    pub fn load(&mut self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        let path = dir.join(STATE_FILE);
+        let data = std::fs::read(&path)
+            .with_context(|| format!("Could not read checkpoint {}", path.display()))?;
+        let (_, header) = SafeTensors::read_metadata(&data)
+            .with_context(|| format!("Invalid checkpoint file {}", path.display()))?;
+        let metadata = header
+            .metadata()
+            .as_ref()
+            .context("Checkpoint file has no metadata.")?;
+        let get = |key: &str| {
+            metadata
+                .get(key)
+                .with_context(|| format!("Checkpoint metadata is missing `{key}`."))
+        };
+        fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T> {
+            value
+                .parse()
+                .ok()
+                .with_context(|| format!("Invalid value `{value}` for `{key}`."))
+        }
+        let version = get("format_version")?;
+        if version != CHECKPOINT_FORMAT_VERSION {
+            bail!("Unsupported checkpoint format version {version}.");
+        }
+
+        let tensors = candle_core::safetensors::load_buffer(&data, &Device::Cpu)?;
+        restore_vars(self.q_vars, &tensors, "q")?;
+        restore_vars(self.target_vars, &tensors, "target")?;
+        let opt_state: HashMap<_, _> = tensors
+            .iter()
+            .filter_map(|(name, t)| Some((name.strip_prefix("opt.")?.to_string(), t.clone())))
+            .collect();
+        self.optimizer
+            .set_state(&opt_state, parse("optimizer_step", get("optimizer_step")?)?)?;
+
+        let seed_hex = get("rng_seed")?;
+        if seed_hex.len() != 64 {
+            bail!("Invalid value `{seed_hex}` for `rng_seed`.");
+        }
+        let mut seed = [0; 32];
+        for (i, byte) in seed.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&seed_hex[i * 2..i * 2 + 2], 16)
+                .ok()
+                .with_context(|| format!("Invalid value `{seed_hex}` for `rng_seed`."))?;
+        }
+        *self.rng = ChaCha8Rng::from_seed(seed);
+        self.rng
+            .set_stream(parse("rng_stream", get("rng_stream")?)?);
+        self.rng
+            .set_word_pos(parse("rng_word_pos", get("rng_word_pos")?)?);
+
+        self.train_env
+            .load_state(get("train_env")?)
+            .context("Could not restore the training environment.")?;
+        self.test_env
+            .load_state(get("test_env")?)
+            .context("Could not restore the test environment.")?;
+        let tensor = |name: &str| {
+            tensors
+                .get(name)
+                .cloned()
+                .with_context(|| format!("Checkpoint is missing `{name}`."))
+        };
+        *self.obs = tensor("obs")?;
+        *self.mask = tensor("mask")?;
+
+        let step: usize = parse("step", get("step")?)?;
+        let buffer_file = get("buffer_file")?;
+        if *buffer_file != format!("{BUFFER_PREFIX}{step}.safetensors") {
+            bail!("Checkpoint at iteration {step} refers to replay buffer {buffer_file}.");
+        }
+        *self.buffer = ReplayBuffer::load(dir.join(buffer_file))?;
+        self.step = step;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::DType;
+    use candle_nn::VarMap;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+    use crate::{
+        env::GridEnv,
+        environment::Environment,
+        level::{LevelGenConfig, LevelGenerator, LevelSource},
+    };
+
+    fn seeded_env(seed: u64) -> GridEnv {
+        let config = LevelGenConfig {
+            seed: Some(seed),
+            ..Default::default()
+        };
+        GridEnv::with_levels(LevelSource::Generator(LevelGenerator::new(config).unwrap()))
+    }
+
+    /// Saves a checkpoint at `step` with the given environments and returns the
+    /// observation it was saved with.
+    fn save(dir: &Path, step: usize, train_env: &mut GridEnv, test_env: &mut GridEnv) -> Tensor {
+        let mut vm = VarMap::new();
+        let mut target_vm = VarMap::new();
+        let mut optimizer = AdamW::new_lr(&vm, 0.001).unwrap();
+        let mut buffer = ReplayBuffer::new(&train_env.observation_space(), 4, 8);
+        let mut obs = Tensor::arange(0f32, 6., &Device::Cpu).unwrap();
+        let mut mask = Tensor::new(&[0f32, 1., 0., 0.], &Device::Cpu).unwrap();
+        Checkpoint {
+            step,
+            config: &TrainConfig::default(),
+            q_vars: &mut vm,
+            target_vars: &mut target_vm,
+            optimizer: &mut optimizer,
+            rng: &mut ChaCha8Rng::seed_from_u64(0),
+            buffer: &mut buffer,
+            train_env,
+            test_env,
+            obs: &mut obs,
+            mask: &mut mask,
+        }
+        .save(dir)
+        .unwrap();
+        obs
+    }
+
+    #[test]
+    fn load_continues_the_same_episodes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut train_env = seeded_env(1);
+        let mut test_env = seeded_env(2);
+        train_env.reset().unwrap();
+        let saved_obs = save(dir.path(), 10, &mut train_env, &mut test_env);
+
+        let mut vm = VarMap::new();
+        let mut target_vm = VarMap::new();
+        let mut optimizer = AdamW::new_lr(&vm, 0.001).unwrap();
+        let mut buffer = ReplayBuffer::new(&train_env.observation_space(), 4, 8);
+        let mut resumed_train = seeded_env(3);
+        let mut resumed_test = seeded_env(4);
+        let mut obs = Tensor::zeros(1, DType::F32, &Device::Cpu).unwrap();
+        let mut mask = Tensor::zeros(1, DType::F32, &Device::Cpu).unwrap();
+        let config = TrainConfig::default();
+        let mut checkpoint = Checkpoint {
+            step: 0,
+            config: &config,
+            q_vars: &mut vm,
+            target_vars: &mut target_vm,
+            optimizer: &mut optimizer,
+            rng: &mut ChaCha8Rng::seed_from_u64(1),
+            buffer: &mut buffer,
+            train_env: &mut resumed_train,
+            test_env: &mut resumed_test,
+            obs: &mut obs,
+            mask: &mut mask,
+        };
+        checkpoint.load(dir.path()).unwrap();
+        assert_eq!(checkpoint.step, 10);
+
+        assert_eq!(resumed_train.grid, train_env.grid);
+        assert_eq!(resumed_train.agent_pos, train_env.agent_pos);
+        assert_eq!(
+            obs.to_vec1::<f32>().unwrap(),
+            saved_obs.to_vec1::<f32>().unwrap()
+        );
+        assert_eq!(mask.to_vec1::<f32>().unwrap(), [0., 1., 0., 0.]);
+        // The level RNGs pick up where they were saved.
+        for _ in 0..3 {
+            assert_eq!(
+                resumed_train.reset().unwrap().0,
+                train_env.reset().unwrap().0
+            );
+            assert_eq!(resumed_test.reset().unwrap().0, test_env.reset().unwrap().0);
+        }
+    }
+
+    #[test]
+    fn save_keeps_only_the_latest_buffer() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut train_env = seeded_env(1);
+        let mut test_env = seeded_env(2);
+        save(dir.path(), 10, &mut train_env, &mut test_env);
+        save(dir.path(), 20, &mut train_env, &mut test_env);
+
+        let mut files: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            [STATE_FILE, CONFIG_FILE, "replay_buffer_20.safetensors"]
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! Command line handling shared by the binaries.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+
+/// Directory models, configs and checkpoints are saved to.
+pub const OUT_DIR: &str = "temp";
+
+/// Returns where the trainer saves the model of `mode`.
+pub fn model_path(mode: &str) -> String {
+    format!("{OUT_DIR}/q_net_{mode}.safetensors")
+}
+
+/// Parses the value of the `--key` option.
+pub fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .ok()
+        .with_context(|| format!("Invalid value `{value}` for `--{key}`."))
+}
+
+/// Options for running a saved model over several episodes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunArgs {
+    /// Arguments that aren't options, in order.
+    pub args: Vec<String>,
+    pub model: Option<String>,
+    pub episodes: usize,
+    pub max_steps: usize,
+    pub seed: u64,
+}
+
+impl RunArgs {
+    /// Parses `--model`, `--episodes`, `--max-steps` and `--seed` options, with
+    /// `episodes` and `max_steps` as defaults. Other `--key=value` options are passed to
+    /// `other`, which returns false if it doesn't know them either.
+    pub fn parse(
+        args: impl IntoIterator<Item = String>,
+        episodes: usize,
+        max_steps: usize,
+        mut other: impl FnMut(&str, &str) -> Result<bool>,
+    ) -> Result<Self> {
+        let (flags, args): (Vec<_>, Vec<_>) =
+            args.into_iter().partition(|arg| arg.starts_with("--"));
+        let mut run = Self {
+            args,
+            model: None,
+            episodes,
+            max_steps,
+            seed: rand::random(),
+        };
+        for flag in &flags {
+            let Some((key, value)) = flag[2..].split_once('=') else {
+                bail!("Unknown option `{flag}`.");
+            };
+            match key {
+                "model" => run.model = Some(value.to_string()),
+                "episodes" => run.episodes = parse_value(key, value)?,
+                "max-steps" => run.max_steps = parse_value(key, value)?,
+                "seed" => run.seed = parse_value(key, value)?,
+                _ if other(key, value)? => {}
+                _ => bail!("Unknown option `{flag}`."),
+            }
+        }
+        Ok(run)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<RunArgs> {
+        RunArgs::parse(args.iter().map(|a| a.to_string()), 5, 100, |key, _| {
+            Ok(key == "delay-ms")
+        })
+    }
+
+    #[test]
+    fn parses_options_and_arguments() {
+        let run = parse(&[
+            "grid",
+            "--episodes=7",
+            "levels",
+            "--seed=3",
+            "--delay-ms=10",
+        ])
+        .unwrap();
+        assert_eq!(run.args, ["grid", "levels"]);
+        assert_eq!((run.episodes, run.max_steps, run.seed), (7, 100, 3));
+        assert_eq!(run.model, None);
+    }
+
+    #[test]
+    fn rejects_unknown_and_invalid_options() {
+        assert!(parse(&["--speed=2"]).is_err());
+        assert!(parse(&["--episodes"]).is_err());
+        assert!(parse(&["--episodes=-1"]).is_err());
+    }
+}
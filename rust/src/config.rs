@@ -0,0 +1,515 @@
+use std::{path::Path, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    metrics::MetricsFormat,
+    model::{MlpConfig, QNetConfig, ValueDistribution},
+};
+
+/// Hyperparameters of a training run.
+///
+/// Loaded from a TOML or JSON file, where missing fields take their default values.
+/// Individual fields can then be overridden with `set`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrainConfig {
+    /// Number of environment steps collected each iteration.
+    pub train_steps: usize,
+    pub iterations: usize,
+    /// Number of passes over the samples collected.
+    pub train_iters: usize,
+    /// Minibatch size while training models.
+    pub train_batch_size: usize,
+    /// Discount factor applied to rewards.
+    pub discount: f64,
+    /// Number of steps to accumulate rewards over before bootstrapping.
+    pub n_step: usize,
+    /// Epsilon for epsilon greedy strategy. This gets annealed over time, and is 0 after
+    /// warmup if the network is noisy.
+    pub q_epsilon: f64,
+    /// Number of eval runs to average over.
+    pub eval_steps: usize,
+    /// Max number of steps to take during each eval run.
+    pub max_eval_steps: usize,
+    /// Number of eval runs in the report after training.
+    pub report_eval_steps: usize,
+    /// Learning rate of the q net.
+    pub q_lr: f64,
+    /// For the first n number of steps, we will only sample randomly.
+    pub warmup_steps: usize,
+    /// Number of elements that can be stored in the buffer.
+    pub buffer_size: usize,
+    /// Number of iterations between saving checkpoints.
+    pub checkpoint_interval: usize,
+    /// Number of iterations before updating Q target.
+    pub target_update: usize,
+    /// Whether next actions are picked by the online network and evaluated by the
+    /// target network, rather than both done by the target network.
+    pub double_q: bool,
+    /// Whether to use prioritized experience replay.
+    pub prioritized: bool,
+    /// How much TD errors affect sampling in the buffer.
+    pub priority_alpha: f64,
+    /// Importance sampling exponent to start with, annealed to 1.
+    pub start_beta: f64,
+    /// Seeds every source of randomness in the run. Picked randomly if not given, so
+    /// saved configs can always reproduce their run.
+    pub seed: u64,
+    /// Format of the per iteration metrics file, `csv`, `jsonl` or `none`.
+    pub metrics_format: MetricsFormat,
+    /// Whether to also write TensorBoard event files.
+    pub tensorboard: bool,
+    /// Architecture of the grid Q network, set from the command line like
+    /// `--model.conv_width=64` or `--model.head_hidden=64,64`.
+    pub model: QNetConfig,
+    /// Architecture of the cartpole Q network, set like `--mlp.hidden=128,128`.
+    pub mlp: MlpConfig,
+    /// What the Q network predicts about returns, and so the loss it's trained with.
+    /// Pick one with `--distribution.kind=c51`, `qr` or `iqn`, then set its fields like
+    /// `--distribution.atoms=101`.
+    pub distribution: ValueDistribution,
+}
+
+impl Default for TrainConfig {
+    fn default() -> Self {
+        Self {
+            train_steps: 20,
+            iterations: 10000,
+            train_iters: 1,
+            train_batch_size: 64,
+            discount: 0.99,
+            n_step: 1,
+            q_epsilon: 0.8,
+            eval_steps: 8,
+            max_eval_steps: 500,
+            report_eval_steps: 100,
+            q_lr: 0.0001,
+            warmup_steps: 500,
+            buffer_size: 10000,
+            checkpoint_interval: 500,
+            target_update: 200,
+            double_q: true,
+            prioritized: false,
+            priority_alpha: 0.6,
+            start_beta: 0.4,
+            // TOML integers are signed, so keep the seed below `i64::MAX`.
+            seed: rand::random::<u64>() >> 1,
+            metrics_format: MetricsFormat::Csv,
+            tensorboard: false,
+            model: QNetConfig::default(),
+            mlp: MlpConfig::default(),
+            distribution: ValueDistribution::Expected,
+        }
+    }
+}
+
+/// A part of Rainbow, which can be switched off to measure what it adds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RainbowComponent {
+    /// Value and advantage streams.
+    Dueling,
+    /// Next actions picked by the online network.
+    DoubleQ,
+    /// Prioritized experience replay.
+    Prioritized,
+    /// Rewards accumulated over several steps.
+    NStep,
+    /// C51 distributions of returns.
+    Distributional,
+    /// `NoisyLinear` layers instead of epsilon-greedy exploration.
+    Noisy,
+}
+
+impl RainbowComponent {
+    pub const ALL: [Self; 6] = [
+        Self::Dueling,
+        Self::DoubleQ,
+        Self::Prioritized,
+        Self::NStep,
+        Self::Distributional,
+        Self::Noisy,
+    ];
+
+    /// Name used on the command line.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Dueling => "dueling",
+            Self::DoubleQ => "double_q",
+            Self::Prioritized => "prioritized",
+            Self::NStep => "n_step",
+            Self::Distributional => "distributional",
+            Self::Noisy => "noisy",
+        }
+    }
+}
+
+impl FromStr for RainbowComponent {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|c| c.name() == s)
+            .with_context(|| {
+                let names: Vec<_> = Self::ALL.iter().map(|c| c.name()).collect();
+                format!(
+                    "Unknown Rainbow component `{s}`, expected one of {}.",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+impl TrainConfig {
+    /// Returns the defaults with every part of Rainbow switched on: dueling networks,
+    /// double Q-learning, prioritized replay, 3 step returns, C51 with atoms from
+    /// `v_min` to `v_max`, and noisy networks. The atoms should cover the discounted
+    /// returns of the environment trained on.
+    pub fn rainbow(v_min: f64, v_max: f64) -> Self {
+        let mut config = Self {
+            double_q: true,
+            prioritized: true,
+            priority_alpha: 0.5,
+            n_step: 3,
+            distribution: ValueDistribution::C51 {
+                atoms: 51,
+                v_min,
+                v_max,
+            },
+            ..Self::default()
+        };
+        config.model.dueling = true;
+        config.model.noisy = true;
+        config.mlp.dueling = true;
+        config.mlp.noisy = true;
+        config
+    }
+
+    /// Switches off a part of Rainbow, falling back to what DQN does.
+    pub fn ablate(&mut self, component: RainbowComponent) {
+        match component {
+            RainbowComponent::Dueling => {
+                self.model.dueling = false;
+                self.mlp.dueling = false;
+            }
+            RainbowComponent::DoubleQ => self.double_q = false,
+            RainbowComponent::Prioritized => self.prioritized = false,
+            RainbowComponent::NStep => self.n_step = 1,
+            RainbowComponent::Distributional => self.distribution = ValueDistribution::Expected,
+            RainbowComponent::Noisy => {
+                self.model.noisy = false;
+                self.mlp.noisy = false;
+            }
+        }
+    }
+
+    /// Loads a config file. The format is picked from the extension, `.toml` or `.json`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read config {}", path.display()))?;
+        let config = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&text).map_err(anyhow::Error::from),
+            Some("json") => serde_json::from_str(&text).map_err(anyhow::Error::from),
+            _ => bail!(
+                "Config {} must have a `.toml` or `.json` extension.",
+                path.display()
+            ),
+        };
+        config.with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    /// Writes the config as TOML.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_toml()?)
+            .with_context(|| format!("Could not save config to {}", path.display()))
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    /// Returns a hash of every field, which stays the same across builds, to tell
+    /// apart models trained with different configs.
+    pub fn hash(&self) -> Result<String> {
+        // 64 bit FNV-1a.
+        let hash = self
+            .to_toml()?
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+                (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+            });
+        Ok(format!("{hash:016x}"))
+    }
+
+    /// Overrides a single field, e.g. from a `--q_lr=0.001` command line option.
+    /// Nested fields are named with dots, like `model.conv_width`, and lists are
+    /// separated by commas.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let mut root = toml::Table::try_from(&*self)?;
+        let (path, field) = match key.rsplit_once('.') {
+            Some((path, field)) => (path.split('.').collect(), field),
+            None => (Vec::new(), key),
+        };
+        let mut table = &mut root;
+        for part in path {
+            table = match table.get_mut(part) {
+                Some(toml::Value::Table(nested)) => nested,
+                _ => bail!("Unknown hyperparameter `{key}`."),
+            };
+        }
+        let integer = |v: &str| {
+            v.trim()
+                .parse()
+                .ok()
+                .filter(|v| *v >= 0)
+                .map(toml::Value::Integer)
+        };
+        let new_value = match table.get(field) {
+            Some(toml::Value::Integer(_)) => integer(value),
+            Some(toml::Value::Float(_)) => value.parse().ok().map(toml::Value::Float),
+            Some(toml::Value::Boolean(_)) => value.parse().ok().map(toml::Value::Boolean),
+            Some(toml::Value::String(_)) => Some(toml::Value::String(value.to_string())),
+            Some(toml::Value::Array(_)) => value
+                .split(',')
+                .filter(|v| !v.trim().is_empty())
+                .map(integer)
+                .collect::<Option<Vec<_>>>()
+                .map(toml::Value::Array),
+            Some(toml::Value::Table(_)) => {
+                bail!("`{key}` is a group of hyperparameters, set its fields like `{key}.<field>`.")
+            }
+            Some(_) => bail!("`{key}` can't be set from the command line."),
+            None => bail!("Unknown hyperparameter `{key}`."),
+        };
+        let expected = match &table[field] {
+            toml::Value::Integer(_) => "a non-negative integer",
+            toml::Value::Float(_) => "a number",
+            toml::Value::Boolean(_) => "`true` or `false`",
+            toml::Value::Array(_) => "comma separated non-negative integers",
+            _ => "a string",
+        };
+        let new_value = new_value.with_context(|| {
+            format!("Invalid value `{value}` for `{key}`, expected {expected}.")
+        })?;
+        table.insert(field.to_string(), new_value);
+        *self = root
+            .try_into()
+            .with_context(|| format!("Invalid value `{value}` for `{key}`."))?;
+        Ok(())
+    }
+
+    /// Checks that all values are in range, listing every problem found.
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = Vec::new();
+        let mut positive = |name: &str, value: usize| {
+            if value == 0 {
+                errors.push(format!("`{name}` must be at least 1."));
+            }
+        };
+        positive("train_steps", self.train_steps);
+        positive("iterations", self.iterations);
+        positive("train_iters", self.train_iters);
+        positive("train_batch_size", self.train_batch_size);
+        positive("n_step", self.n_step);
+        positive("eval_steps", self.eval_steps);
+        positive("max_eval_steps", self.max_eval_steps);
+        positive("report_eval_steps", self.report_eval_steps);
+        positive("buffer_size", self.buffer_size);
+        positive("checkpoint_interval", self.checkpoint_interval);
+        positive("target_update", self.target_update);
+        positive("model.conv_width", self.model.conv_width);
+        positive("model.recurrence", self.model.recurrence);
+        for &size in &self.model.head_hidden {
+            positive("model.head_hidden", size);
+        }
+        for &size in &self.mlp.hidden {
+            positive("mlp.hidden", size);
+        }
+        match self.distribution {
+            ValueDistribution::Qr { quantiles, .. } => {
+                positive("distribution.quantiles", quantiles);
+            }
+            ValueDistribution::Iqn {
+                samples,
+                policy_samples,
+                embedding_dim,
+                ..
+            } => {
+                positive("distribution.samples", samples);
+                positive("distribution.policy_samples", policy_samples);
+                positive("distribution.embedding_dim", embedding_dim);
+            }
+            _ => {}
+        }
+
+        if let ValueDistribution::C51 {
+            atoms,
+            v_min,
+            v_max,
+        } = self.distribution
+        {
+            if atoms < 2 {
+                errors.push(format!(
+                    "`distribution.atoms` must be at least 2, found {atoms}."
+                ));
+            }
+            if !(v_min < v_max && v_min.is_finite() && v_max.is_finite()) {
+                errors.push(format!(
+                    "`distribution.v_min` ({v_min}) must be less than `distribution.v_max` ({v_max})."
+                ));
+            }
+        }
+        if let ValueDistribution::Qr { kappa, cvar, .. }
+        | ValueDistribution::Iqn { kappa, cvar, .. } = self.distribution
+        {
+            if !(kappa > 0. && kappa.is_finite()) {
+                errors.push(format!(
+                    "`distribution.kappa` must be positive, found {kappa}."
+                ));
+            }
+            if !(cvar > 0. && cvar <= 1.) {
+                errors.push(format!(
+                    "`distribution.cvar` must be above 0 and at most 1, found {cvar}."
+                ));
+            }
+        }
+
+        let mut unit = |name: &str, value: f64| {
+            if !(0. ..=1.).contains(&value) {
+                errors.push(format!("`{name}` must be between 0 and 1, found {value}."));
+            }
+        };
+        unit("discount", self.discount);
+        unit("q_epsilon", self.q_epsilon);
+        unit("start_beta", self.start_beta);
+
+        if !(self.q_lr > 0. && self.q_lr.is_finite()) {
+            errors.push(format!("`q_lr` must be positive, found {}.", self.q_lr));
+        }
+        if !(self.priority_alpha >= 0. && self.priority_alpha.is_finite()) {
+            errors.push(format!(
+                "`priority_alpha` must not be negative, found {}.",
+                self.priority_alpha
+            ));
+        }
+        if self.buffer_size < self.train_batch_size {
+            errors.push(format!(
+                "`buffer_size` ({}) must be at least `train_batch_size` ({}).",
+                self.buffer_size, self.train_batch_size
+            ));
+        }
+
+        if !errors.is_empty() {
+            bail!("Invalid hyperparameters:\n  {}", errors.join("\n  "));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_parses_values_by_their_type() {
+        let mut config = TrainConfig::default();
+        config.set("iterations", "42").unwrap();
+        config.set("q_lr", "0.01").unwrap();
+        config.set("double_q", "false").unwrap();
+        config.set("model.head_hidden", "64, 32").unwrap();
+        assert_eq!(config.iterations, 42);
+        assert_eq!(config.q_lr, 0.01);
+        assert!(!config.double_q);
+        assert_eq!(config.model.head_hidden, [64, 32]);
+    }
+
+    #[test]
+    fn set_switches_distribution_kind() {
+        let mut config = TrainConfig::default();
+        config.set("distribution.kind", "c51").unwrap();
+        config.set("distribution.atoms", "101").unwrap();
+        assert!(matches!(
+            config.distribution,
+            ValueDistribution::C51 { atoms: 101, .. }
+        ));
+        assert!(config.set("distribution.kind", "gaussian").is_err());
+    }
+
+    #[test]
+    fn set_rejects_unknown_keys() {
+        let mut config = TrainConfig::default();
+        let before = config.clone();
+        for key in ["learning_rate", "model.width", "optimizer.lr", "model"] {
+            assert!(config.set(key, "1").is_err(), "`{key}` was accepted");
+        }
+        assert_eq!(config, before);
+    }
+
+    #[test]
+    fn set_rejects_negative_integers() {
+        let mut config = TrainConfig::default();
+        let err = config.set("iterations", "-5").unwrap_err();
+        assert!(err.to_string().contains("non-negative integer"), "{err}");
+        assert!(config.set("model.head_hidden", "64,-1").is_err());
+        assert!(config.set("iterations", "1.5").is_err());
+    }
+
+    /// Which parts of Rainbow `config` uses, in the order of `RainbowComponent::ALL`.
+    /// Network parts count as used if either network has them.
+    fn components(config: &TrainConfig) -> Vec<bool> {
+        vec![
+            config.model.dueling || config.mlp.dueling,
+            config.double_q,
+            config.prioritized,
+            config.n_step > 1,
+            config.distribution != ValueDistribution::Expected,
+            config.model.noisy || config.mlp.noisy,
+        ]
+    }
+
+    #[test]
+    fn each_ablation_switches_off_one_component() {
+        let rainbow = TrainConfig::rainbow(-1., 1.);
+        assert!(rainbow.model.dueling && rainbow.mlp.dueling);
+        assert!(rainbow.model.noisy && rainbow.mlp.noisy);
+        assert_eq!(components(&rainbow), [true; 6]);
+        rainbow.validate().unwrap();
+
+        let mut all_off = rainbow.clone();
+        for (i, component) in RainbowComponent::ALL.into_iter().enumerate() {
+            let mut config = rainbow.clone();
+            config.ablate(component);
+            let mut expected = [true; 6];
+            expected[i] = false;
+            assert_eq!(components(&config), expected, "{component:?}");
+            config.validate().unwrap();
+            assert_eq!(
+                component.name().parse::<RainbowComponent>().unwrap(),
+                component
+            );
+            all_off.ablate(component);
+        }
+        assert_eq!(components(&all_off), [false; 6]);
+    }
+
+    #[test]
+    fn validate_lists_out_of_range_values() {
+        let mut config = TrainConfig::default();
+        config.validate().unwrap();
+        config.set("start_beta", "1.5").unwrap();
+        config.set("train_batch_size", "0").unwrap();
+        let err = config.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("`start_beta` must be between 0 and 1"),
+            "{err}"
+        );
+        assert!(
+            err.contains("`train_batch_size` must be at least 1"),
+            "{err}"
+        );
+    }
+}
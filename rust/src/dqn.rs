@@ -1,14 +1,66 @@
 use anyhow::Result;
-use candle_core::{backprop::GradStore, DType, Device, IndexOp, Module, Tensor};
-use candle_nn::{Optimizer, VarBuilder, VarMap};
+use candle_core::{DType, Device, Tensor, D};
+use candle_nn::{ops, Optimizer, VarMap};
+use rand::Rng;
 
-use crate::replay_buffer::ReplayBuffer;
+use crate::{
+    evaluate::INFINITY,
+    model::{quantile_midpoints, support, QModel, ValueDistribution},
+    replay_buffer::ReplayBuffer,
+};
 
-const INFINITY: f64 = 9999.9;
+/// Statistics from one call to `train_dqn`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrainStats {
+    /// Q loss summed over all training iterations.
+    pub total_q_loss: f32,
+    /// Mean absolute TD error over all sampled transitions.
+    pub mean_abs_td: f32,
+    /// Mean, min and max predicted Q-value of the actions taken in sampled transitions.
+    pub q_mean: f32,
+    pub q_min: f32,
+    pub q_max: f32,
+    /// Predicted Q-values of the actions taken in sampled transitions.
+    pub q_values: Vec<f32>,
+}
+
+/// A minibatch sampled from the replay buffer.
+struct Batch {
+    prev_states: Tensor,
+    states: Tensor,
+    actions: Tensor,
+    rewards: Tensor,
+    dones: Tensor,
+    discounts: Tensor,
+    /// Best allowed action in each next state, which the target network evaluates.
+    next_actions: Tensor,
+}
+
+/// The loss of a minibatch and what's logged about it.
+struct BatchLoss {
+    /// Loss of each sample, before importance sampling weights are applied.
+    loss: Tensor,
+    /// Difference between the expected target and the predicted Q-value of each sample.
+    td_errors: Vec<f32>,
+    /// Error each sample's priority is set from.
+    priority_errors: Vec<f32>,
+    /// Predicted Q-value of each action taken.
+    q_pred: Vec<f32>,
+}
 
 /// Performs the DQN training loop.
+///
+/// `beta` is the importance sampling exponent used if the buffer is prioritized, and
+/// minibatches are drawn with `rng`.
+/// Bootstrapped values are discounted by the per transition discount stored in the
+/// buffer, which is `discount^n` for n-step transitions. With `double_q`, next actions
+/// are picked by `q_net` rather than `q_net_target`.
+///
+/// The loss follows what the network predicts: squared TD errors for expected values,
+/// the cross-entropy with the projected target distribution for C51, and the quantile
+/// Huber loss for QR-DQN and IQN. Distributional losses also set priorities.
 #[allow(clippy::too_many_arguments)]
-pub fn train_dqn<M: Module, O: Optimizer>(
+pub fn train_dqn<M: QModel, O: Optimizer>(
     q_net: &M,
     q_net_target: &M,
     q_opt: &mut O,
@@ -17,54 +69,65 @@ pub fn train_dqn<M: Module, O: Optimizer>(
     device: &Device,
     train_iters: usize,
     train_batch_size: usize,
-    discount: f64,
-    priority: f64,
-) -> Result<f32> {
+    beta: f32,
+    double_q: bool,
+    rng: &mut impl Rng,
+) -> Result<TrainStats> {
     let mut total_q_loss = 0.;
+    let mut td_sum = 0.;
+    let mut q_values = Vec::with_capacity(train_iters * train_batch_size);
     for v in vm.all_vars() {
         v.to_device(device)?;
     }
 
     for _ in 0..train_iters {
-        let (indices, probs, prev_states, states, actions, rewards, dones, masks) =
-            buffer.sample(train_batch_size)?;
+        let (indices, weights, prev_states, states, actions, rewards, dones, masks, discounts) =
+            buffer.sample(train_batch_size, beta, rng)?;
+
+        // Independent noise for each network if noisy.
+        q_net.resample_noise(rng)?;
+        q_net_target.resample_noise(rng)?;
 
         // Move batch to device if applicable
-        let prev_states = prev_states.to_device(device)?;
         let states = states.to_device(device)?;
-        let actions = actions.to_device(device)?;
-        let rewards = rewards.to_device(device)?;
-        let dones = dones.to_device(device)?;
-        let masks = masks.to_device(device)?;
+        // Double Q-learning picks next actions with the online network, so the target
+        // network doesn't evaluate its own choices.
+        let selector = if double_q { q_net } else { q_net_target };
+        let next_actions = best_actions(selector, &states, &masks.to_device(device)?)?;
+        let batch = Batch {
+            prev_states: prev_states.to_device(device)?,
+            states,
+            actions: actions.to_device(device)?,
+            rewards: rewards.to_device(device)?,
+            dones: dones.to_device(device)?,
+            discounts: discounts.to_device(device)?,
+            next_actions,
+        };
+        let weights = weights.to_device(device)?;
 
         // Train q network
-        // q_opt.zero_grad();
-        let next_actions = (q_net.forward(&states)? * (1. - &masks)? + (&masks * -INFINITY)?)?
-            .argmax(1)?
-            .detach()?
-            .squeeze(0)?;
-        let q_target = (&rewards
-            + discount
-                * (q_net_target
-                    .forward(&states)?
-                    .detach()?
-                    .gather(&next_actions.unsqueeze(1)?, 1)?
-                    .squeeze(1)?
-                    .to_dtype(DType::F32)?
-                    * (1. - &dones)?)?)?;
-        let q_pred = q_net
-            .forward(&prev_states)?
-            .gather(&actions.unsqueeze(1)?, 1)?
-            .squeeze(1)?;
-        let diff = (q_target - &q_pred)?;
-        let q_loss = (
-            // (1. / probs)?.powf(priority) * 
-            (&diff * &diff)
-            // ?
-        )?.mean(0)?;
+        let batch_loss = match *q_net.distribution() {
+            ValueDistribution::Expected => expected_loss(q_net, q_net_target, &batch)?,
+            ValueDistribution::C51 {
+                atoms,
+                v_min,
+                v_max,
+            } => categorical_loss(q_net, q_net_target, &batch, &support(atoms, v_min, v_max))?,
+            ValueDistribution::Qr { kappa, .. } => {
+                quantile_loss(q_net, q_net_target, &batch, kappa, None, rng)?
+            }
+            ValueDistribution::Iqn { samples, kappa, .. } => {
+                quantile_loss(q_net, q_net_target, &batch, kappa, Some(samples), rng)?
+            }
+        };
+        let q_loss = (&weights * &batch_loss.loss)?.mean(0)?;
         q_opt.backward_step(&q_loss)?;
         total_q_loss += q_loss.to_scalar::<f32>()?;
-        buffer.update_errors(&indices, &diff.to_vec1()?)
+        td_sum += batch_loss.td_errors.iter().map(|e| e.abs()).sum::<f32>();
+        q_values.extend(batch_loss.q_pred);
+        if buffer.prioritized {
+            buffer.update_errors(&indices, &batch_loss.priority_errors);
+        }
     }
 
     if device.is_cpu() {
@@ -72,5 +135,281 @@ pub fn train_dqn<M: Module, O: Optimizer>(
             v.to_device(&Device::Cpu)?;
         }
     }
-    Ok(total_q_loss)
+    let sample_count = (train_iters * train_batch_size) as f32;
+    Ok(TrainStats {
+        total_q_loss,
+        mean_abs_td: td_sum / sample_count,
+        q_mean: q_values.iter().sum::<f32>() / sample_count,
+        q_min: q_values.iter().copied().fold(f32::INFINITY, f32::min),
+        q_max: q_values.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+        q_values,
+    })
+}
+
+/// Picks the best allowed action in each state.
+fn best_actions<M: QModel>(
+    q_net: &M,
+    states: &Tensor,
+    masks: &Tensor,
+) -> candle_core::Result<Tensor> {
+    let q_vals = (q_net.forward(states)? * (1. - masks)? + (masks * -INFINITY)?)?;
+    Ok(q_vals.argmax(1)?.detach()?)
+}
+
+/// Squared TD error of expected Q-values.
+fn expected_loss<M: QModel>(q_net: &M, q_net_target: &M, batch: &Batch) -> Result<BatchLoss> {
+    let q_target = (&batch.rewards
+        + (&batch.discounts
+            * (q_net_target
+                .forward(&batch.states)?
+                .detach()?
+                .gather(&batch.next_actions.unsqueeze(1)?, 1)?
+                .squeeze(1)?
+                .to_dtype(DType::F32)?
+                * (1. - &batch.dones)?)?)?)?;
+    let q_pred = q_net
+        .forward(&batch.prev_states)?
+        .gather(&batch.actions.unsqueeze(1)?, 1)?
+        .squeeze(1)?;
+    let diff = (q_target - &q_pred)?;
+    let errors = diff.to_vec1::<f32>()?;
+    Ok(BatchLoss {
+        loss: (&diff * &diff)?,
+        td_errors: errors.clone(),
+        priority_errors: errors,
+        q_pred: q_pred.to_vec1()?,
+    })
+}
+
+/// Cross-entropy between the C51 distribution of each action taken and its target,
+/// the target network's distribution of the next action shifted by the Bellman update
+/// and projected back onto `support`.
+fn categorical_loss<M: QModel>(
+    q_net: &M,
+    q_net_target: &M,
+    batch: &Batch,
+    support: &[f32],
+) -> Result<BatchLoss> {
+    let atoms = support.len();
+    let device = batch.rewards.device();
+    let next_probs = ops::softmax(
+        &q_net_target
+            .forward_dist(&batch.states, None)?
+            .detach()?
+            .gather(&atom_index(&batch.next_actions, atoms)?, 1)?
+            .squeeze(1)?,
+        D::Minus1,
+    )?;
+    let target = project(
+        &next_probs.to_vec2::<f32>()?,
+        support,
+        &batch.rewards.to_vec1::<f32>()?,
+        &batch.discounts.to_vec1::<f32>()?,
+        &batch.dones.to_vec1::<f32>()?,
+    );
+    let target = Tensor::from_vec(target, (next_probs.dim(0)?, atoms), device)?;
+
+    let logits = q_net
+        .forward_dist(&batch.prev_states, None)?
+        .gather(&atom_index(&batch.actions, atoms)?, 1)?
+        .squeeze(1)?;
+    let log_probs = ops::log_softmax(&logits, D::Minus1)?;
+    let loss = (&target * &log_probs)?.sum(1)?.neg()?;
+
+    let support = Tensor::new(support, device)?;
+    let q_pred = log_probs.detach()?.exp()?.broadcast_mul(&support)?.sum(1)?;
+    let q_target = target.broadcast_mul(&support)?.sum(1)?;
+    Ok(BatchLoss {
+        td_errors: (q_target - &q_pred)?.to_vec1()?,
+        priority_errors: loss.to_vec1()?,
+        q_pred: q_pred.to_vec1()?,
+        loss,
+    })
+}
+
+/// Quantile Huber loss between the quantiles of each action taken and the target
+/// network's quantiles of the next action after the Bellman update, summed over
+/// predicted quantiles and averaged over target ones.
+///
+/// QR-DQN predicts fixed quantiles. IQN predicts `samples` fractions drawn from `rng`,
+/// separately for predictions and targets.
+fn quantile_loss<M: QModel>(
+    q_net: &M,
+    q_net_target: &M,
+    batch: &Batch,
+    kappa: f64,
+    samples: Option<usize>,
+    rng: &mut impl Rng,
+) -> Result<BatchLoss> {
+    let device = batch.rewards.device();
+    let batch_size = batch.rewards.dim(0)?;
+    let mut sample_taus = |n: usize| {
+        let taus: Vec<f32> = (0..batch_size * n).map(|_| rng.gen()).collect();
+        Tensor::from_vec(taus, (batch_size, n), device)
+    };
+    let (taus, target_taus) = match samples {
+        Some(n) => (sample_taus(n)?, Some(sample_taus(n)?)),
+        None => {
+            let quantiles = q_net.distribution().outputs();
+            let taus = Tensor::new(quantile_midpoints(quantiles), device)?
+                .unsqueeze(0)?
+                .repeat(&[batch_size, 1])?;
+            (taus, None)
+        }
+    };
+
+    let next_quantiles = q_net_target
+        .forward_dist(&batch.states, target_taus.as_ref())?
+        .detach()?;
+    let next_quantiles = next_quantiles
+        .gather(&atom_index(&batch.next_actions, next_quantiles.dim(2)?)?, 1)?
+        .squeeze(1)?;
+    let discounts = (&batch.discounts * (1. - &batch.dones)?)?;
+    let target = next_quantiles
+        .broadcast_mul(&discounts.unsqueeze(1)?)?
+        .broadcast_add(&batch.rewards.unsqueeze(1)?)?;
+
+    let quantiles = q_net.forward_dist(&batch.prev_states, Some(&taus))?;
+    let quantiles = quantiles
+        .gather(&atom_index(&batch.actions, quantiles.dim(2)?)?, 1)?
+        .squeeze(1)?;
+    let loss = quantile_huber(&quantiles, &taus, &target, kappa)?;
+
+    let q_pred = quantiles.detach()?.mean(1)?;
+    Ok(BatchLoss {
+        td_errors: (target.mean(1)? - &q_pred)?.to_vec1()?,
+        priority_errors: loss.to_vec1()?,
+        q_pred: q_pred.to_vec1()?,
+        loss,
+    })
+}
+
+/// Quantile Huber loss of `quantiles` at fractions `taus`, both of shape (batch, n),
+/// against `target` returns of shape (batch, m). Returns the loss of each sample.
+fn quantile_huber(
+    quantiles: &Tensor,
+    taus: &Tensor,
+    target: &Tensor,
+    kappa: f64,
+) -> candle_core::Result<Tensor> {
+    // Pairwise errors of shape (batch, predicted, target).
+    let errors = target
+        .unsqueeze(1)?
+        .broadcast_sub(&quantiles.unsqueeze(2)?)?;
+    let abs_errors = errors.abs()?;
+    let clipped = abs_errors.clamp(0., kappa)?;
+    let huber = (((&clipped * &clipped)? * 0.5)? + ((abs_errors - &clipped)? * kappa)?)?;
+    // Over- and underestimates are weighted by the fraction each quantile stands for.
+    let below = errors.lt(&errors.zeros_like()?)?.to_dtype(DType::F32)?;
+    let weight = taus.unsqueeze(2)?.broadcast_sub(&below)?.abs()?;
+    ((weight * huber)? / kappa)?.mean(2)?.sum(1)
+}
+
+/// Turns actions of shape (batch) into indices that gather each action's outputs from
+/// outputs of shape (batch, actions, n).
+fn atom_index(actions: &Tensor, n: usize) -> candle_core::Result<Tensor> {
+    actions.reshape((actions.dim(0)?, 1, 1))?.repeat(&[1, 1, n])
+}
+
+/// Moves each atom of `support` to `reward + discount * atom`, or to `reward` if the
+/// episode ended, then splits its probability between the two nearest atoms.
+/// Returns one row of probabilities per sample, flattened.
+fn project(
+    probs: &[Vec<f32>],
+    support: &[f32],
+    rewards: &[f32],
+    discounts: &[f32],
+    dones: &[f32],
+) -> Vec<f32> {
+    let atoms = support.len();
+    let (v_min, v_max) = (support[0], support[atoms - 1]);
+    let delta = (v_max - v_min) / (atoms - 1) as f32;
+    let mut target = vec![0.; probs.len() * atoms];
+    for (i, (row, out)) in probs.iter().zip(target.chunks_mut(atoms)).enumerate() {
+        let discount = discounts[i] * (1. - dones[i]);
+        for (&z, &p) in support.iter().zip(row) {
+            let pos = ((rewards[i] + discount * z).clamp(v_min, v_max) - v_min) / delta;
+            let lower = (pos.floor() as usize).min(atoms - 1);
+            let upper = (pos.ceil() as usize).min(atoms - 1);
+            if lower == upper {
+                out[lower] += p;
+            } else {
+                out[lower] += p * (upper as f32 - pos);
+                out[upper] += p * (pos - lower as f32);
+            }
+        }
+    }
+    target
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_rows_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "{actual:?} != {expected:?}");
+        }
+    }
+
+    fn huber(quantiles: &[f32], taus: &[f32], target: &[f32], kappa: f64) -> f32 {
+        let row = |values: &[f32]| Tensor::new(values, &Device::Cpu)?.unsqueeze(0);
+        quantile_huber(
+            &row(quantiles).unwrap(),
+            &row(taus).unwrap(),
+            &row(target).unwrap(),
+            kappa,
+        )
+        .unwrap()
+        .squeeze(0)
+        .unwrap()
+        .to_scalar()
+        .unwrap()
+    }
+
+    #[test]
+    fn quantile_huber_weights_errors_by_tau() {
+        // An error of 2 with kappa 1 is in the linear part: 0.5 + (2 - 1) = 1.5.
+        // Targets above the quantile are weighted by tau, those below by 1 - tau.
+        assert_eq!(huber(&[0.], &[0.25], &[2.], 1.), 0.25 * 1.5);
+        assert_eq!(huber(&[0.], &[0.25], &[-2.], 1.), 0.75 * 1.5);
+        assert_eq!(huber(&[0.], &[0.75], &[-2.], 1.), 0.25 * 1.5);
+        // Averaged over targets.
+        assert_eq!(huber(&[0.], &[0.25], &[2., -2.], 1.), (0.375 + 1.125) / 2.);
+        // Errors below kappa are quadratic, then divided by kappa: 0.5 * 0.5^2 / 2.
+        assert_eq!(huber(&[1.], &[0.5], &[1.5], 2.), 0.5 * 0.0625);
+        // Summed over predicted quantiles, each with its own tau: the first is below the
+        // target and weighted by tau, the second above it and weighted by 1 - tau.
+        assert_eq!(
+            huber(&[0., 4.], &[0.25, 0.4], &[2.], 1.),
+            0.25 * 1.5 + 0.6 * 1.5
+        );
+    }
+
+    #[test]
+    fn project_moves_mass_to_shifted_atoms() {
+        // Atoms at -2, -1, 0, 1 and 2.
+        let support = support(5, -2., 2.);
+        let probs = vec![vec![0.1, 0.2, 0.4, 0.2, 0.1]; 4];
+        let target = project(
+            &probs,
+            &support,
+            &[0.5, 1., 0.5, 4.],
+            &[0.9, 1., 1., 1.],
+            &[1., 0., 0., 0.],
+        );
+        let rows: Vec<_> = target.chunks(5).collect();
+        // Done: every atom moves to the reward, halfway between 0 and 1.
+        assert_rows_close(rows[0], &[0., 0., 0.5, 0.5, 0.]);
+        // Exact hits: shifted up one atom, with the top atom's mass clamped onto it.
+        assert_rows_close(rows[1], &[0., 0.1, 0.2, 0.4, 0.3]);
+        // Splits: each atom lands halfway between two, except the clamped top one.
+        assert_rows_close(rows[2], &[0.05, 0.15, 0.3, 0.3, 0.2]);
+        // Rewards above the support clamp everything to the top atom.
+        assert_rows_close(rows[3], &[0., 0., 0., 0., 1.]);
+        for row in rows {
+            assert!((row.iter().sum::<f32>() - 1.).abs() < 1e-6);
+        }
+    }
 }
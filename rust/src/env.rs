@@ -1,108 +1,290 @@
 use std::collections::VecDeque;
 
-use rand::Rng;
+use anyhow::{bail, Result};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
 
-pub const GRID_SIZE: usize = 6;
-const COIN_IDX: usize = 0;
-const PIT_IDX: usize = 1;
-const WALL_IDX: usize = 2;
-const BOX_IDX: usize = 3;
+use crate::{
+    environment::{ActSpace, EnvState, Environment, ObsSpace, RgbImage, StepInfo},
+    level::{Level, LevelGenConfig, LevelGenerator, LevelSource},
+};
+
+/// Width and height of generated levels if not configured.
+pub const DEFAULT_GRID_SIZE: usize = 6;
+pub(crate) const COIN_IDX: usize = 0;
+pub(crate) const PIT_IDX: usize = 1;
+pub(crate) const WALL_IDX: usize = 2;
+pub(crate) const BOX_IDX: usize = 3;
 pub const NUM_CHANNELS: usize = BOX_IDX + 1 + 2;
 const MAX_TIME: u32 = 16;
+/// Size of each cell in pixels when rendered as an image.
+const CELL_PIXELS: usize = 16;
 
 pub type State = Vec<Vec<Vec<bool>>>;
 type Position = (usize, usize);
 
 /// Gym-like interface for the environment.
 pub struct GridEnv {
+    /// Width of the current level.
+    pub width: usize,
+    /// Height of the current level.
+    pub height: usize,
     /// Stack of 2D arrays.
     pub grid: Vec<Vec<Vec<bool>>>,
     pub goal_pos: Position,
     pub agent_pos: Position,
     pub timer: u32,
     pub pos_buf: VecDeque<Position>,
+    /// Provides a new level on each reset.
+    pub levels: LevelSource,
 }
 
-/// Returns the position of an empty cell.
-fn get_empty(ref_grid: &[usize], taken: &[Position]) -> Position {
-    let mut rng = rand::thread_rng();
-    let mut new_pos = (rng.gen_range(0..GRID_SIZE), rng.gen_range(0..GRID_SIZE));
-    while ref_grid[new_pos.1 * GRID_SIZE + new_pos.0] != 0 || taken.contains(&new_pos) {
-        new_pos = (rng.gen_range(0..GRID_SIZE), rng.gen_range(0..GRID_SIZE));
+impl GridEnv {
+    /// Creates an environment on randomly generated levels of the default size.
+    pub fn new() -> Result<Self> {
+        Ok(Self::with_levels(LevelSource::Generator(
+            LevelGenerator::new(LevelGenConfig::default())?,
+        )))
     }
-    new_pos
-}
 
-impl GridEnv {
-    pub fn new() -> Self {
+    pub fn with_levels(levels: LevelSource) -> Self {
+        let (width, height) = levels
+            .size()
+            .unwrap_or((DEFAULT_GRID_SIZE, DEFAULT_GRID_SIZE));
         Self {
+            width,
+            height,
             grid: Vec::new(),
             goal_pos: (0, 0),
             agent_pos: (0, 0),
             timer: 0,
             pos_buf: VecDeque::new(),
+            levels,
         }
     }
 
-    pub fn reset(&mut self) -> (State, Vec<bool>) {
-        let mut rng = rand::thread_rng();
-        let ref_grid = vec![
-            3, 3, 3, 3, 3, 3, 3, 0, 0, 3, 0, 3, 3, 1, 0, 0, 0, 3, 3, 1, 2, 3, 0, 3, 3, 0, 0, 3, 1,
-            3, 3, 3, 3, 3, 3, 3,
-        ];
-        // let ref_grid = loop {
-        //     let mut ref_grid: Vec<_> = (0..(GRID_SIZE * GRID_SIZE))
-        //         .map(|_| rng.gen_range(0..(WALL_IDX + 2)))
-        //         .collect();
-        //     for y in 0..GRID_SIZE {
-        //         for x in 0..GRID_SIZE {
-        //             if (1..GRID_SIZE - 1).contains(&x) && (1..GRID_SIZE - 1).contains(&y) {
-        //                 continue;
-        //             }
-        //             ref_grid[y * GRID_SIZE + x] = WALL_IDX + 1;
-        //         }
-        //     }
-        //     if ref_grid.iter().filter(|&&c| c == 0).count() > 2
-        //         && ref_grid.iter().filter(|&&c| c == WALL_IDX + 1).count()
-        //             <= (GRID_SIZE * 4 - 4 + 2)
-        //         && ref_grid.iter().filter(|&&c| c == PIT_IDX + 1).count() <= 1
-        //         && ref_grid.iter().filter(|&&c| c == BOX_IDX + 1).count() <= 1
-        //     {
-        //         break ref_grid;
-        //     }
-        // };
-        let mut grid = vec![vec![vec![false; GRID_SIZE]; GRID_SIZE]; BOX_IDX + 1];
-        for (i, &val) in ref_grid.iter().enumerate() {
-            let y = i / GRID_SIZE;
-            let x = i % GRID_SIZE;
+    /// Starts a new episode on the given level.
+    pub fn load_level(&mut self, level: &Level) -> (State, StepInfo) {
+        self.width = level.width;
+        self.height = level.height;
+        let mut grid = vec![vec![vec![false; self.width]; self.height]; BOX_IDX + 1];
+        for (i, &val) in level.cells.iter().enumerate() {
+            let y = i / self.width;
+            let x = i % self.width;
             if val > 0 {
                 grid[val - 1][y][x] = true;
             }
         }
-        let goal_pos = (4, 1);//get_empty(&ref_grid, &[]);
-        let agent_pos = (1, 4);//get_empty(&ref_grid, &[goal_pos, (1, 3), (1, 2), (3, 1), (3, 3), (3, 4)]);
-        *self = Self {
-            grid,
-            goal_pos,
-            agent_pos,
-            timer: 0,
-            pos_buf: VecDeque::new(),
-        };
-        let (x, y) = agent_pos;
-        let masks = vec![
+        self.grid = grid;
+        self.goal_pos = level.goal_pos;
+        self.agent_pos = level.agent_pos;
+        self.timer = 0;
+        self.pos_buf.clear();
+        (
+            self.get_obs(),
+            StepInfo {
+                terminated: false,
+                truncated: false,
+                action_mask: self.masks(),
+            },
+        )
+    }
+
+    /// Returns the current state as a level, with collected coins and moved boxes.
+    pub fn to_level(&self) -> Level {
+        let mut cells = vec![0; self.width * self.height];
+        for (layer, plane) in self.grid.iter().enumerate() {
+            for (y, row) in plane.iter().enumerate() {
+                for (x, &set) in row.iter().enumerate() {
+                    if set {
+                        cells[y * self.width + x] = layer + 1;
+                    }
+                }
+            }
+        }
+        Level {
+            width: self.width,
+            height: self.height,
+            cells,
+            goal_pos: self.goal_pos,
+            agent_pos: self.agent_pos,
+        }
+    }
+
+    fn masks(&self) -> Vec<bool> {
+        let (x, y) = self.agent_pos;
+        vec![
             self.grid[WALL_IDX][y][x - 1],
             self.grid[WALL_IDX][y][x + 1],
             self.grid[WALL_IDX][y - 1][x],
             self.grid[WALL_IDX][y + 1][x],
+        ]
+    }
+
+    fn is_border(&self, x: i32, y: i32) -> bool {
+        x < 0 || x >= self.width as i32 || y < 0 || y >= self.height as i32
+    }
+
+    fn get_obs(&self) -> State {
+        let mut state = self.grid.clone();
+        let mut goal_layer = vec![vec![false; self.width]; self.height];
+        goal_layer[self.goal_pos.1][self.goal_pos.0] = true;
+        let mut agent_layer = vec![vec![false; self.width]; self.height];
+        agent_layer[self.agent_pos.1][self.agent_pos.0] = true;
+        state.push(goal_layer);
+        state.push(agent_layer);
+        state
+    }
+
+    /// Prints the current state to the terminal, using the glyphs of level files.
+    pub fn render(&self) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.grid[COIN_IDX][y][x] {
+                    print!("@");
+                } else if self.grid[PIT_IDX][y][x] {
+                    print!("!");
+                } else if self.grid[WALL_IDX][y][x] {
+                    print!("*");
+                } else if self.grid[BOX_IDX][y][x] {
+                    print!("O");
+                } else if self.goal_pos == (x, y) {
+                    print!("G");
+                } else if self.agent_pos == (x, y) {
+                    print!("A");
+                } else {
+                    print!(" ");
+                }
+            }
+            println!();
+        }
+    }
+}
+
+/// What `GridEnv::save_state` writes. Levels aren't saved, only where their RNG is.
+#[derive(Serialize, Deserialize)]
+struct GridEnvState {
+    width: usize,
+    height: usize,
+    grid: State,
+    goal_pos: Position,
+    agent_pos: Position,
+    timer: u32,
+    pos_buf: VecDeque<Position>,
+    level_rng: ChaCha12Rng,
+}
+
+impl EnvState for GridEnv {
+    fn save_state(&self) -> Result<String> {
+        Ok(serde_json::to_string(&GridEnvState {
+            width: self.width,
+            height: self.height,
+            grid: self.grid.clone(),
+            goal_pos: self.goal_pos,
+            agent_pos: self.agent_pos,
+            timer: self.timer,
+            pos_buf: self.pos_buf.clone(),
+            level_rng: self.levels.rng().clone(),
+        })?)
+    }
+
+    fn load_state(&mut self, state: &str) -> Result<()> {
+        let state: GridEnvState = serde_json::from_str(state)?;
+        let size_matches = |plane: &Vec<Vec<bool>>| {
+            plane.len() == state.height && plane.iter().all(|row| row.len() == state.width)
+        };
+        // The grid is empty if no level was loaded yet.
+        let layers_match = state.grid.is_empty() || state.grid.len() == BOX_IDX + 1;
+        if !layers_match || !state.grid.iter().all(size_matches) {
+            bail!(
+                "Saved grid does not match its size of {}x{}.",
+                state.width,
+                state.height
+            );
+        }
+        self.width = state.width;
+        self.height = state.height;
+        self.grid = state.grid;
+        self.goal_pos = state.goal_pos;
+        self.agent_pos = state.agent_pos;
+        self.timer = state.timer;
+        self.pos_buf = state.pos_buf;
+        *self.levels.rng_mut() = state.level_rng;
+        Ok(())
+    }
+}
+
+impl Environment for GridEnv {
+    type Obs = State;
+
+    const NAME: &'static str = "grid";
+    const VERSION: u32 = 1;
+
+    /// The spatial size follows the current level, so it may change on reset
+    /// if the levels have different sizes.
+    fn observation_space(&self) -> ObsSpace {
+        ObsSpace::MultiBinary(vec![NUM_CHANNELS, self.height, self.width])
+    }
+
+    fn action_space(&self) -> ActSpace {
+        ActSpace::Discrete(4)
+    }
+
+    fn reset(&mut self) -> Result<(State, StepInfo)> {
+        let level = self.levels.next_level()?;
+        Ok(self.load_level(&level))
+    }
+
+    fn is_success(&self) -> bool {
+        self.agent_pos == self.goal_pos
+    }
+
+    /// Draws each cell as a colored square, with the goal and agent drawn smaller on top.
+    fn render_image(&self) -> Option<RgbImage> {
+        // No level has been loaded yet.
+        if self.grid.is_empty() {
+            return None;
+        }
+        let mut image = RgbImage::new(self.width * CELL_PIXELS, self.height * CELL_PIXELS);
+        let layers = [
+            (COIN_IDX, [230, 190, 40]),
+            (PIT_IDX, [20, 20, 20]),
+            (WALL_IDX, [110, 110, 120]),
+            (BOX_IDX, [150, 100, 50]),
         ];
-        // Call this again if we're walled off.
-        if !masks.contains(&false) {
-            return self.reset();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let color = layers
+                    .iter()
+                    .find(|(layer, _)| self.grid[*layer][y][x])
+                    .map(|(_, color)| *color)
+                    .unwrap_or([235, 235, 235]);
+                image.fill_rect(
+                    x * CELL_PIXELS,
+                    y * CELL_PIXELS,
+                    CELL_PIXELS,
+                    CELL_PIXELS,
+                    color,
+                );
+            }
+        }
+        let inset = CELL_PIXELS / 4;
+        for ((x, y), color) in [
+            (self.goal_pos, [40, 180, 60]),
+            (self.agent_pos, [40, 90, 220]),
+        ] {
+            image.fill_rect(
+                x * CELL_PIXELS + inset,
+                y * CELL_PIXELS + inset,
+                CELL_PIXELS - inset * 2,
+                CELL_PIXELS - inset * 2,
+                color,
+            );
         }
-        (self.get_obs(), masks)
+        Some(image)
     }
 
-    pub fn step(&mut self, action: u32) -> (State, f32, bool, bool, Vec<bool>) {
+    fn step(&mut self, action: u32) -> (State, f32, StepInfo) {
         let mut dx = 0;
         let mut dy = 0;
         match action {
@@ -110,11 +292,11 @@ impl GridEnv {
             1 => dx = 1,
             2 => dy = -1,
             3 => dy = 1,
-            _ => panic!(),
+            _ => panic!("Action {action} is out of range, there are 4 actions."),
         }
 
-        let mut x = (self.agent_pos.0 as i32 + dx).clamp(0, GRID_SIZE as i32 - 1) as usize;
-        let mut y = (self.agent_pos.1 as i32 + dy).clamp(0, GRID_SIZE as i32 - 1) as usize;
+        let mut x = (self.agent_pos.0 as i32 + dx).clamp(0, self.width as i32 - 1) as usize;
+        let mut y = (self.agent_pos.1 as i32 + dy).clamp(0, self.height as i32 - 1) as usize;
         let mut reward = -0.001;
         let mut done = false;
 
@@ -126,7 +308,7 @@ impl GridEnv {
         else if self.grid[BOX_IDX][y][x] {
             let bx = x as i32 + dx;
             let by = y as i32 + dy;
-            if is_border(bx, by)
+            if self.is_border(bx, by)
                 || self.grid[WALL_IDX][by as usize][bx as usize]
                 || self.grid[BOX_IDX][by as usize][bx as usize]
             {
@@ -152,55 +334,19 @@ impl GridEnv {
             done = true;
         }
 
-        let masks = vec![
-            self.grid[WALL_IDX][y][x - 1],
-            self.grid[WALL_IDX][y][x + 1],
-            self.grid[WALL_IDX][y - 1][x],
-            self.grid[WALL_IDX][y + 1][x],
-        ];
         self.agent_pos = (x, y);
 
         self.timer += 1;
         let trunc = self.timer >= MAX_TIME;
 
-        (self.get_obs(), reward, done, trunc, masks)
-    }
-
-    fn get_obs(&self) -> State {
-        let mut state = self.grid.clone();
-        let mut goal_layer = vec![vec![false; GRID_SIZE]; GRID_SIZE];
-        goal_layer[self.goal_pos.1][self.goal_pos.0] = true;
-        let mut agent_layer = vec![vec![false; GRID_SIZE]; GRID_SIZE];
-        agent_layer[self.agent_pos.1][self.agent_pos.0] = true;
-        state.push(goal_layer);
-        state.push(agent_layer);
-        state
-    }
-
-    pub fn render(&self) {
-        for y in 0..GRID_SIZE {
-            for x in 0..GRID_SIZE {
-                if self.grid[COIN_IDX][y][x] {
-                    print!("@");
-                } else if self.grid[PIT_IDX][y][x] {
-                    print!("!");
-                } else if self.grid[WALL_IDX][y][x] {
-                    print!("*");
-                } else if self.grid[BOX_IDX][y][x] {
-                    print!("O");
-                } else if self.goal_pos == (x, y) {
-                    print!("G");
-                } else if self.agent_pos == (x, y) {
-                    print!("A");
-                } else {
-                    print!(" ");
-                }
-            }
-            println!();
-        }
-        std::thread::sleep(std::time::Duration::from_secs(1));
+        (
+            self.get_obs(),
+            reward,
+            StepInfo {
+                terminated: done,
+                truncated: trunc,
+                action_mask: self.masks(),
+            },
+        )
     }
 }
-fn is_border(x: i32, y: i32) -> bool {
-    x < 0 || x >= GRID_SIZE as i32 || y < 0 || y >= GRID_SIZE as i32
-}
@@ -0,0 +1,195 @@
+use anyhow::Result;
+
+/// Describes the observations an environment produces.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObsSpace {
+    /// Stack of binary planes, e.g. the layers of a grid.
+    MultiBinary(Vec<usize>),
+    /// Real valued vector, bounded elementwise by `low` and `high`.
+    Box { low: Vec<f32>, high: Vec<f32> },
+}
+
+impl ObsSpace {
+    /// Returns the shape of a single observation.
+    pub fn shape(&self) -> Vec<usize> {
+        match self {
+            ObsSpace::MultiBinary(shape) => shape.clone(),
+            ObsSpace::Box { low, .. } => vec![low.len()],
+        }
+    }
+
+    /// Returns the number of elements in a single observation.
+    pub fn size(&self) -> usize {
+        self.shape().iter().product()
+    }
+}
+
+/// Describes the actions an environment accepts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ActSpace {
+    /// Actions are integers in `0..n`.
+    Discrete(usize),
+}
+
+impl ActSpace {
+    /// Returns the number of actions.
+    pub fn size(&self) -> usize {
+        match self {
+            ActSpace::Discrete(n) => *n,
+        }
+    }
+}
+
+/// Extra information returned alongside each observation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepInfo {
+    /// The episode ended because a terminal state was reached.
+    pub terminated: bool,
+    /// The episode was cut short, e.g. by a time limit.
+    pub truncated: bool,
+    /// One entry per action. `true` means the action is not allowed.
+    pub action_mask: Vec<bool>,
+}
+
+impl StepInfo {
+    /// Info with no actions masked and the episode still running.
+    pub fn unmasked(action_count: usize) -> Self {
+        Self {
+            terminated: false,
+            truncated: false,
+            action_mask: vec![false; action_count],
+        }
+    }
+
+    /// Returns true if the episode is over for any reason.
+    pub fn done(&self) -> bool {
+        self.terminated || self.truncated
+    }
+}
+
+/// An environment whose exact state, including its RNG, can be saved and restored,
+/// so training resumed from a checkpoint continues the same episodes.
+pub trait EnvState {
+    /// Returns the current state as JSON.
+    fn save_state(&self) -> Result<String>;
+
+    /// Restores a state from `save_state`. The environment must have been created with
+    /// the same settings, e.g. the same levels.
+    fn load_state(&mut self, state: &str) -> Result<()>;
+}
+
+/// An observation that can be flattened into network input.
+pub trait Observation {
+    /// Returns the shape of this observation.
+    fn shape(&self) -> Vec<usize>;
+
+    /// Returns the observation as a flat, row major vector.
+    fn to_vec(&self) -> Vec<f32>;
+}
+
+impl Observation for Vec<Vec<Vec<bool>>> {
+    fn shape(&self) -> Vec<usize> {
+        let height = self.first().map_or(0, Vec::len);
+        let width = self
+            .first()
+            .and_then(|rows| rows.first())
+            .map_or(0, Vec::len);
+        vec![self.len(), height, width]
+    }
+
+    fn to_vec(&self) -> Vec<f32> {
+        self.iter()
+            .flatten()
+            .flatten()
+            .map(|&b| if b { 1. } else { 0. })
+            .collect()
+    }
+}
+
+impl Observation for (f32, f32, f32, f32) {
+    fn shape(&self) -> Vec<usize> {
+        vec![4]
+    }
+
+    fn to_vec(&self) -> Vec<f32> {
+        vec![self.0, self.1, self.2, self.3]
+    }
+}
+
+/// An 8 bit RGB image, stored row major.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RgbImage {
+    pub width: usize,
+    pub height: usize,
+    /// Three bytes per pixel.
+    pub pixels: Vec<u8>,
+}
+
+impl RgbImage {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; width * height * 3],
+        }
+    }
+
+    /// Fills a rectangle with a color, clipped to the image.
+    pub fn fill_rect(&mut self, x: usize, y: usize, w: usize, h: usize, color: [u8; 3]) {
+        for py in y..(y + h).min(self.height) {
+            for px in x..(x + w).min(self.width) {
+                let i = (py * self.width + px) * 3;
+                self.pixels[i..i + 3].copy_from_slice(&color);
+            }
+        }
+    }
+}
+
+/// Gym-like interface shared by all environments.
+pub trait Environment {
+    type Obs: Observation;
+
+    /// Identifies the environment in saved models.
+    const NAME: &'static str;
+    /// Changed whenever the dynamics or observations change, so models trained on
+    /// another version are rejected.
+    const VERSION: u32;
+
+    fn observation_space(&self) -> ObsSpace;
+
+    fn action_space(&self) -> ActSpace;
+
+    /// Starts a new episode. Fails if no starting state can be made, e.g. when
+    /// a level can't be generated.
+    fn reset(&mut self) -> Result<(Self::Obs, StepInfo)>;
+
+    /// Advances the environment by one step.
+    ///
+    /// `action` must be below `action_space().size()`. Environments may panic on other
+    /// actions, so check actions that come from outside, e.g. a user, first.
+    fn step(&mut self, action: u32) -> (Self::Obs, f32, StepInfo);
+
+    /// Returns true if the episode that just ended counts as a success, e.g. because
+    /// the goal was reached. Only meaningful once `step` reports the episode is done.
+    fn is_success(&self) -> bool {
+        false
+    }
+
+    /// Draws the current state, if the environment supports it.
+    fn render_image(&self) -> Option<RgbImage> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid_shape_handles_empty_grids() {
+        let grid = vec![vec![vec![false; 4]; 3]; 2];
+        assert_eq!(grid.shape(), [2, 3, 4]);
+        assert_eq!(Vec::<Vec<Vec<bool>>>::new().shape(), [0, 0, 0]);
+        assert_eq!(vec![Vec::<Vec<bool>>::new()].shape(), [1, 0, 0]);
+    }
+}
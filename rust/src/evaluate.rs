@@ -0,0 +1,347 @@
+use std::fmt::{self, Display};
+
+use anyhow::Result;
+use candle_core::{Device, Module, Tensor};
+
+use crate::environment::{Environment, Observation};
+
+/// Subtracted from the Q-values of masked actions so they're never picked.
+pub(crate) const INFINITY: f64 = 9999.9;
+
+/// Two sided 97.5% quantiles of Student's t-distribution for 1 to 30 degrees of freedom.
+/// Larger samples use the normal approximation.
+const T_QUANTILES: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+const Z_95: f64 = 1.96;
+
+pub fn process_obs<O: Observation>(obs: &O) -> Result<Tensor> {
+    Ok(Tensor::from_vec(obs.to_vec(), obs.shape(), &Device::Cpu)?.unsqueeze(0)?)
+}
+
+pub fn mask_to_tensor(mask: &[bool]) -> candle_core::Result<Tensor> {
+    Tensor::new(
+        mask.iter().map(|&b| b as u8 as f32).collect::<Vec<_>>(),
+        &Device::Cpu,
+    )?
+    .unsqueeze(0)
+}
+
+/// Pushes the Q-values of masked actions far below any allowed action's, as done
+/// during training. `mask` holds 1 for each masked action.
+pub fn apply_mask(q_vals: &Tensor, mask: &Tensor) -> candle_core::Result<Tensor> {
+    (q_vals * (1. - mask)?)? + (mask * -INFINITY)?
+}
+
+/// Picks actions during evaluation.
+pub trait Policy {
+    /// Returns the action to take given a batch of one observation.
+    /// Actions where `mask` is `true` are not allowed.
+    fn act(&mut self, obs: &Tensor, mask: &[bool]) -> Result<u32>;
+}
+
+/// Always takes the allowed action with the highest Q-value.
+pub struct GreedyPolicy<'a, M: Module + ?Sized> {
+    pub q_net: &'a M,
+}
+
+impl<'a, M: Module + ?Sized> GreedyPolicy<'a, M> {
+    pub fn new(q_net: &'a M) -> Self {
+        Self { q_net }
+    }
+}
+
+impl<M: Module + ?Sized> Policy for GreedyPolicy<'_, M> {
+    fn act(&mut self, obs: &Tensor, mask: &[bool]) -> Result<u32> {
+        let mask = mask_to_tensor(mask)?.squeeze(0)?;
+        let q_vals = apply_mask(&self.q_net.forward(obs)?.detach()?.squeeze(0)?, &mask)?;
+        Ok(q_vals.argmax(0)?.to_scalar()?)
+    }
+}
+
+/// How an episode ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The environment reported success, e.g. the goal was reached.
+    Success,
+    /// The episode terminated without success, e.g. by falling in a pit.
+    Failure,
+    /// The episode hit a time limit.
+    Timeout,
+}
+
+/// The result of one evaluation episode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EpisodeResult {
+    pub episode_return: f32,
+    pub length: usize,
+    pub outcome: Outcome,
+}
+
+/// Mean of a sample with a 95% confidence interval.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    pub mean: f64,
+    pub std: f64,
+    /// Lower and upper bound of the confidence interval.
+    pub ci: (f64, f64),
+}
+
+impl Estimate {
+    /// Uses Student's t-distribution, so the interval is reasonable for few episodes.
+    fn of(values: &[f64]) -> Self {
+        let n = values.len();
+        if n == 0 {
+            return Self {
+                mean: f64::NAN,
+                std: f64::NAN,
+                ci: (f64::NAN, f64::NAN),
+            };
+        }
+        let mean = values.iter().sum::<f64>() / n as f64;
+        if n == 1 {
+            return Self {
+                mean,
+                std: 0.,
+                ci: (mean, mean),
+            };
+        }
+        // Sample standard deviation.
+        let std = (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1) as f64).sqrt();
+        let t = T_QUANTILES.get(n - 2).copied().unwrap_or(Z_95);
+        let half_width = t * std / (n as f64).sqrt();
+        Self {
+            mean,
+            std,
+            ci: (mean - half_width, mean + half_width),
+        }
+    }
+}
+
+/// Results of all evaluation episodes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvalReport {
+    pub episodes: Vec<EpisodeResult>,
+}
+
+impl EvalReport {
+    pub fn returns(&self) -> Estimate {
+        let values: Vec<_> = self
+            .episodes
+            .iter()
+            .map(|e| e.episode_return as f64)
+            .collect();
+        Estimate::of(&values)
+    }
+
+    pub fn lengths(&self) -> Estimate {
+        let values: Vec<_> = self.episodes.iter().map(|e| e.length as f64).collect();
+        Estimate::of(&values)
+    }
+
+    /// Fraction of episodes with the given outcome.
+    pub fn rate(&self, outcome: Outcome) -> f64 {
+        self.count(outcome) as f64 / self.episodes.len() as f64
+    }
+
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.episodes
+            .iter()
+            .filter(|e| e.outcome == outcome)
+            .count()
+    }
+
+    /// 95% Wilson score interval of the success rate, which stays within `[0, 1]` and
+    /// behaves well when nearly every or no episode succeeds.
+    pub fn success_ci(&self) -> (f64, f64) {
+        let n = self.episodes.len() as f64;
+        let p = self.rate(Outcome::Success);
+        let z2 = Z_95 * Z_95;
+        let center = (p + z2 / (2. * n)) / (1. + z2 / n);
+        let half_width = Z_95 * (p * (1. - p) / n + z2 / (4. * n * n)).sqrt() / (1. + z2 / n);
+        ((center - half_width).max(0.), (center + half_width).min(1.))
+    }
+
+    pub fn min_return(&self) -> f32 {
+        self.episodes
+            .iter()
+            .map(|e| e.episode_return)
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    pub fn max_return(&self) -> f32 {
+        self.episodes
+            .iter()
+            .map(|e| e.episode_return)
+            .fold(f32::NEG_INFINITY, f32::max)
+    }
+}
+
+impl Display for EvalReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let returns = self.returns();
+        let lengths = self.lengths();
+        let success_ci = self.success_ci();
+        writeln!(f, "Evaluation over {} episodes:", self.episodes.len())?;
+        writeln!(
+            f,
+            "  Return: {:.3} +/- {:.3} (95% CI {:.3} to {:.3})",
+            returns.mean, returns.std, returns.ci.0, returns.ci.1
+        )?;
+        writeln!(
+            f,
+            "  Min/max return: {:.3} / {:.3}",
+            self.min_return(),
+            self.max_return()
+        )?;
+        writeln!(
+            f,
+            "  Episode length: {:.1} (95% CI {:.1} to {:.1})",
+            lengths.mean, lengths.ci.0, lengths.ci.1
+        )?;
+        writeln!(
+            f,
+            "  Success rate: {:.1}% (95% CI {:.1}% to {:.1}%)",
+            self.rate(Outcome::Success) * 100.,
+            success_ci.0 * 100.,
+            success_ci.1 * 100.
+        )?;
+        write!(
+            f,
+            "  Failures: {}, timeouts: {}",
+            self.count(Outcome::Failure),
+            self.count(Outcome::Timeout)
+        )
+    }
+}
+
+/// Progress of an evaluation episode, passed to the callback of `evaluate_with`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EpisodeStep {
+    pub episode: usize,
+    /// Steps taken so far, 0 right after the reset.
+    pub step: usize,
+    pub episode_return: f32,
+    /// Set on the last step of the episode.
+    pub outcome: Option<Outcome>,
+}
+
+/// Runs `episodes` episodes with `policy`, each cut off after `max_steps` steps.
+pub fn evaluate<E: Environment, P: Policy>(
+    policy: &mut P,
+    env: &mut E,
+    episodes: usize,
+    max_steps: usize,
+) -> Result<EvalReport> {
+    evaluate_with(policy, env, episodes, max_steps, |_, _| Ok(()))
+}
+
+/// Like `evaluate`, but calls `on_step` after each reset and step, e.g. to render
+/// the environment.
+pub fn evaluate_with<E: Environment, P: Policy>(
+    policy: &mut P,
+    env: &mut E,
+    episodes: usize,
+    max_steps: usize,
+    mut on_step: impl FnMut(&E, &EpisodeStep) -> Result<()>,
+) -> Result<EvalReport> {
+    let mut results = Vec::with_capacity(episodes);
+    for episode in 0..episodes {
+        let (obs_, info) = env.reset()?;
+        let mut obs = process_obs(&obs_)?;
+        let mut mask = info.action_mask;
+        let mut progress = EpisodeStep {
+            episode,
+            step: 0,
+            episode_return: 0.,
+            outcome: None,
+        };
+        on_step(env, &progress)?;
+        let mut outcome = Outcome::Timeout;
+        while progress.step < max_steps {
+            let action = policy.act(&obs, &mask)?;
+            let (obs_, reward, info) = env.step(action);
+            obs = process_obs(&obs_)?;
+            progress.episode_return += reward;
+            progress.step += 1;
+            if info.done() {
+                outcome = if env.is_success() {
+                    Outcome::Success
+                } else if info.terminated {
+                    Outcome::Failure
+                } else {
+                    Outcome::Timeout
+                };
+            }
+            if info.done() || progress.step == max_steps {
+                progress.outcome = Some(outcome);
+            }
+            on_step(env, &progress)?;
+            if info.done() {
+                break;
+            }
+            mask = info.action_mask;
+        }
+        results.push(EpisodeResult {
+            episode_return: progress.episode_return,
+            length: progress.step,
+            outcome,
+        });
+    }
+    Ok(EvalReport { episodes: results })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: (f64, f64), expected: (f64, f64)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-4 && (actual.1 - expected.1).abs() < 1e-4,
+            "{actual:?} != {expected:?}"
+        );
+    }
+
+    fn report(successes: usize, failures: usize) -> EvalReport {
+        let episode = |outcome| EpisodeResult {
+            episode_return: 0.,
+            length: 1,
+            outcome,
+        };
+        let mut episodes = vec![episode(Outcome::Success); successes];
+        episodes.extend(vec![episode(Outcome::Failure); failures]);
+        EvalReport { episodes }
+    }
+
+    #[test]
+    fn estimate_uses_t_quantiles() {
+        assert!(Estimate::of(&[]).mean.is_nan());
+        assert_eq!(Estimate::of(&[5.]).ci, (5., 5.));
+        // One degree of freedom, with a standard error of 1.
+        let estimate = Estimate::of(&[1., 3.]);
+        assert_eq!((estimate.mean, estimate.std), (2., 2_f64.sqrt()));
+        assert_close(estimate.ci, (2. - 12.706, 2. + 12.706));
+        // Two degrees of freedom, with a standard deviation of 1.
+        let half_width = 4.303 / 3_f64.sqrt();
+        assert_close(
+            Estimate::of(&[1., 2., 3.]).ci,
+            (2. - half_width, 2. + half_width),
+        );
+        // Past the table, the normal quantile is used.
+        let values: Vec<_> = (0..40).map(|i| (i % 2) as f64).collect();
+        let estimate = Estimate::of(&values);
+        let half_width = Z_95 * estimate.std / 40_f64.sqrt();
+        assert_close(estimate.ci, (0.5 - half_width, 0.5 + half_width));
+    }
+
+    #[test]
+    fn success_ci_is_the_wilson_interval() {
+        assert_close(report(1, 0).success_ci(), (0.2065, 1.));
+        assert_close(report(0, 1).success_ci(), (0., 0.7935));
+        assert_close(report(10, 0).success_ci(), (0.7225, 1.));
+        assert_close(report(0, 10).success_ci(), (0., 0.2775));
+        assert_close(report(5, 5).success_ci(), (0.2366, 0.7634));
+    }
+}
@@ -0,0 +1,649 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt,
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+
+use crate::env::{BOX_IDX, COIN_IDX, DEFAULT_GRID_SIZE, PIT_IDX, WALL_IDX};
+
+/// Number of attempts made before giving up on generating a level.
+const MAX_ATTEMPTS: usize = 10000;
+/// Upper bound on states visited by the solvability search.
+const MAX_SEARCH_STATES: usize = 100000;
+/// Smallest width or height a level can have, a border around one row or column.
+const MIN_GRID_SIZE: usize = 3;
+/// Fewest inner cells a level can have, one for the agent and one for the goal.
+const MIN_INNER_CELLS: usize = 2;
+
+type Position = (usize, usize);
+
+/// A single map for `GridEnv`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Level {
+    pub width: usize,
+    pub height: usize,
+    /// Row major cells. 0 is empty, otherwise the value is the layer index plus 1.
+    pub cells: Vec<usize>,
+    pub goal_pos: Position,
+    pub agent_pos: Position,
+}
+
+impl Level {
+    pub fn get(&self, (x, y): Position) -> usize {
+        self.cells[y * self.width + x]
+    }
+
+    /// Returns the position moved by `(dx, dy)`, or `None` if it leaves the level.
+    fn offset(&self, (x, y): Position, dx: i32, dy: i32) -> Option<Position> {
+        let x = x as i32 + dx;
+        let y = y as i32 + dy;
+        if x < 0 || x >= self.width as i32 || y < 0 || y >= self.height as i32 {
+            None
+        } else {
+            Some((x as usize, y as usize))
+        }
+    }
+
+    /// Returns true if the agent can reach the goal.
+    ///
+    /// Walls and pits block movement. Boxes block movement unless they can be pushed,
+    /// so the search runs over agent and box positions together.
+    pub fn is_solvable(&self) -> bool {
+        let is_blocked = |(x, y): Position| {
+            let cell = self.get((x, y));
+            cell == WALL_IDX + 1 || cell == PIT_IDX + 1
+        };
+        let mut boxes: Vec<_> = (0..self.cells.len())
+            .filter(|&i| self.cells[i] == BOX_IDX + 1)
+            .map(|i| (i % self.width, i / self.width))
+            .collect();
+        boxes.sort();
+
+        let start = (self.agent_pos, boxes);
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::new();
+        seen.insert(start.clone());
+        queue.push_back(start);
+        while let Some((agent_pos, boxes)) = queue.pop_front() {
+            if agent_pos == self.goal_pos {
+                return true;
+            }
+            if seen.len() > MAX_SEARCH_STATES {
+                return false;
+            }
+            for (dx, dy) in [(-1, 0), (1, 0), (0, -1), (0, 1)] {
+                let Some(pos) = self.offset(agent_pos, dx, dy) else {
+                    continue;
+                };
+                if self.get(pos) == WALL_IDX + 1 {
+                    continue;
+                }
+                let mut boxes = boxes.clone();
+                if let Some(box_i) = boxes.iter().position(|&b| b == pos) {
+                    // Boxes can be pushed over anything except walls and other boxes.
+                    match self.offset(pos, dx, dy) {
+                        Some(box_pos)
+                            if self.get(box_pos) != WALL_IDX + 1 && !boxes.contains(&box_pos) =>
+                        {
+                            boxes[box_i] = box_pos;
+                            boxes.sort();
+                        }
+                        _ => continue,
+                    }
+                } else if is_blocked(pos) {
+                    continue;
+                }
+                let state = (pos, boxes);
+                if seen.insert(state.clone()) {
+                    queue.push_back(state);
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Returns the glyph used for a cell in level files.
+fn cell_glyph(cell: usize) -> char {
+    match cell {
+        0 => ' ',
+        c if c == COIN_IDX + 1 => '@',
+        c if c == PIT_IDX + 1 => '!',
+        c if c == WALL_IDX + 1 => '*',
+        c if c == BOX_IDX + 1 => 'O',
+        _ => unreachable!(),
+    }
+}
+
+/// An error from parsing a level file. Lines and columns start at 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseLevelError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseLevelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for ParseLevelError {}
+
+fn parse_error(line: usize, column: usize, message: impl Into<String>) -> ParseLevelError {
+    ParseLevelError {
+        line,
+        column,
+        message: message.into(),
+    }
+}
+
+/// Parses a level using the same glyphs as `GridEnv::render`.
+///
+/// `@` is a coin, `!` a pit, `*` a wall, `O` a box, `G` the goal and `A` the agent.
+/// Empty cells are spaces or `.`. The outer border must be walls.
+impl FromStr for Level {
+    type Err = ParseLevelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lines: Vec<_> = s
+            .trim_end_matches(['\n', '\r'])
+            .lines()
+            .map(|l| l.trim_end_matches('\r'))
+            .collect();
+        if lines.len() < MIN_GRID_SIZE {
+            return Err(parse_error(
+                lines.len() + 1,
+                1,
+                format!(
+                    "expected at least {MIN_GRID_SIZE} rows, found {}",
+                    lines.len()
+                ),
+            ));
+        }
+        let height = lines.len();
+        let width = lines[0].chars().count();
+        if width < MIN_GRID_SIZE {
+            return Err(parse_error(
+                1,
+                width + 1,
+                format!("expected at least {MIN_GRID_SIZE} columns, found {width}"),
+            ));
+        }
+
+        let mut cells = vec![0; width * height];
+        let mut goal_pos = None;
+        let mut agent_pos = None;
+        for (y, line) in lines.iter().enumerate() {
+            let row: Vec<_> = line.chars().collect();
+            if row.len() != width {
+                return Err(parse_error(
+                    y + 1,
+                    row.len().min(width) + 1,
+                    format!(
+                        "expected {width} columns like the first row, found {}",
+                        row.len()
+                    ),
+                ));
+            }
+            for (x, &c) in row.iter().enumerate() {
+                let cell = match c {
+                    ' ' | '.' => 0,
+                    '@' => COIN_IDX + 1,
+                    '!' => PIT_IDX + 1,
+                    '*' => WALL_IDX + 1,
+                    'O' => BOX_IDX + 1,
+                    'G' | 'A' => {
+                        let pos = if c == 'G' {
+                            &mut goal_pos
+                        } else {
+                            &mut agent_pos
+                        };
+                        if pos.is_some() {
+                            return Err(parse_error(y + 1, x + 1, format!("duplicate `{c}`")));
+                        }
+                        *pos = Some((x, y));
+                        0
+                    }
+                    _ => return Err(parse_error(y + 1, x + 1, format!("unknown glyph `{c}`"))),
+                };
+                let on_border = x == 0 || y == 0 || x == width - 1 || y == height - 1;
+                if on_border && cell != WALL_IDX + 1 {
+                    return Err(parse_error(y + 1, x + 1, "border cells must be walls"));
+                }
+                cells[y * width + x] = cell;
+            }
+        }
+
+        let missing = |glyph| parse_error(height, 1, format!("missing `{glyph}`"));
+        let level = Level {
+            width,
+            height,
+            cells,
+            goal_pos: goal_pos.ok_or_else(|| missing('G'))?,
+            agent_pos: agent_pos.ok_or_else(|| missing('A'))?,
+        };
+        // Every action would be masked. The agent is never on the border, so all
+        // of its neighbours are inside the level.
+        let (x, y) = level.agent_pos;
+        let neighbours = [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)];
+        if neighbours.iter().all(|&pos| level.get(pos) == WALL_IDX + 1) {
+            return Err(parse_error(y + 1, x + 1, "the agent is walled in"));
+        }
+        Ok(level)
+    }
+}
+
+/// Writes a level in the format read by `Level::from_str`.
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let glyph = if self.goal_pos == (x, y) {
+                    'G'
+                } else if self.agent_pos == (x, y) {
+                    'A'
+                } else {
+                    cell_glyph(self.get((x, y)))
+                };
+                write!(f, "{glyph}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// A fixed collection of levels, sampled uniformly on reset.
+pub struct LevelSet {
+    pub levels: Vec<Level>,
+    rng: ChaCha12Rng,
+}
+
+impl LevelSet {
+    pub fn new(levels: Vec<Level>, seed: Option<u64>) -> Result<Self> {
+        if levels.is_empty() {
+            bail!("A level set needs at least one level.");
+        }
+        let rng = match seed {
+            Some(seed) => ChaCha12Rng::seed_from_u64(seed),
+            None => ChaCha12Rng::from_entropy(),
+        };
+        Ok(Self { levels, rng })
+    }
+
+    /// Loads every `.txt` file in a directory as a level.
+    pub fn load_dir(dir: impl AsRef<Path>, seed: Option<u64>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let mut paths = Vec::new();
+        for entry in std::fs::read_dir(dir)
+            .with_context(|| format!("Could not read level directory {}", dir.display()))?
+        {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "txt") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        let mut levels = Vec::new();
+        for path in paths {
+            let text = std::fs::read_to_string(&path)
+                .with_context(|| format!("Could not read level {}", path.display()))?;
+            let level = text
+                .parse::<Level>()
+                .with_context(|| format!("Invalid level {}", path.display()))?;
+            levels.push(level);
+        }
+        if levels.is_empty() {
+            bail!("No `.txt` levels found in {}.", dir.display());
+        }
+        Self::new(levels, seed)
+    }
+
+    /// Returns a random level from the set.
+    pub fn sample(&mut self) -> Level {
+        self.levels[self.rng.gen_range(0..self.levels.len())].clone()
+    }
+}
+
+/// Where `GridEnv` gets its levels from.
+pub enum LevelSource {
+    Generator(LevelGenerator),
+    Set(LevelSet),
+}
+
+impl LevelSource {
+    /// Loads the levels in `dir` if given, otherwise generates levels with default settings.
+    pub fn load_or_generate(dir: Option<impl AsRef<Path>>, seed: Option<u64>) -> Result<Self> {
+        Ok(match dir {
+            Some(dir) => LevelSource::Set(LevelSet::load_dir(dir, seed)?),
+            None => LevelSource::Generator(LevelGenerator::new(LevelGenConfig {
+                seed,
+                ..Default::default()
+            })?),
+        })
+    }
+
+    /// Returns the width and height of the next level, if all levels share one size.
+    pub fn size(&self) -> Option<(usize, usize)> {
+        match self {
+            LevelSource::Generator(generator) => {
+                Some((generator.config.width, generator.config.height))
+            }
+            LevelSource::Set(set) => {
+                let first = &set.levels[0];
+                set.levels
+                    .iter()
+                    .all(|l| (l.width, l.height) == (first.width, first.height))
+                    .then_some((first.width, first.height))
+            }
+        }
+    }
+
+    /// Returns the RNG that picks or generates levels, so checkpoints can save it.
+    pub fn rng(&self) -> &ChaCha12Rng {
+        match self {
+            LevelSource::Generator(generator) => &generator.rng,
+            LevelSource::Set(set) => &set.rng,
+        }
+    }
+
+    pub fn rng_mut(&mut self) -> &mut ChaCha12Rng {
+        match self {
+            LevelSource::Generator(generator) => &mut generator.rng,
+            LevelSource::Set(set) => &mut set.rng,
+        }
+    }
+
+    pub fn next_level(&mut self) -> Result<Level> {
+        Ok(match self {
+            LevelSource::Generator(generator) => generator.generate()?,
+            LevelSource::Set(set) => set.sample(),
+        })
+    }
+}
+
+/// Settings for procedurally generated levels.
+///
+/// Densities are the probability of an inner cell holding that object.
+#[derive(Debug, Clone)]
+pub struct LevelGenConfig {
+    /// Width of the level, including the border walls.
+    pub width: usize,
+    /// Height of the level, including the border walls.
+    pub height: usize,
+    pub coin_density: f32,
+    pub pit_density: f32,
+    pub wall_density: f32,
+    pub box_density: f32,
+    /// Seed for the generator. If `None`, a random seed is used.
+    pub seed: Option<u64>,
+}
+
+impl Default for LevelGenConfig {
+    fn default() -> Self {
+        Self {
+            width: DEFAULT_GRID_SIZE,
+            height: DEFAULT_GRID_SIZE,
+            coin_density: 0.1,
+            pit_density: 0.05,
+            wall_density: 0.15,
+            box_density: 0.05,
+            seed: None,
+        }
+    }
+}
+
+/// Generates random, solvable levels.
+pub struct LevelGenerator {
+    config: LevelGenConfig,
+    rng: ChaCha12Rng,
+}
+
+impl LevelGenerator {
+    pub fn new(config: LevelGenConfig) -> Result<Self> {
+        if config.width < MIN_GRID_SIZE || config.height < MIN_GRID_SIZE {
+            bail!(
+                "Levels must be at least {MIN_GRID_SIZE}x{MIN_GRID_SIZE}, got {}x{}.",
+                config.width,
+                config.height
+            );
+        }
+        if (config.width - 2) * (config.height - 2) < MIN_INNER_CELLS {
+            bail!(
+                "Levels need at least {MIN_INNER_CELLS} cells inside the border walls, got {}x{}.",
+                config.width,
+                config.height
+            );
+        }
+        let densities = [
+            config.coin_density,
+            config.pit_density,
+            config.wall_density,
+            config.box_density,
+        ];
+        if densities.iter().any(|d| !(0.0..=1.0).contains(d)) {
+            bail!("Level densities must be between 0 and 1, got {densities:?}.");
+        }
+        if densities.iter().sum::<f32>() >= 1. {
+            bail!("Level densities must sum to less than 1, got {densities:?}.");
+        }
+        let rng = match config.seed {
+            Some(seed) => ChaCha12Rng::seed_from_u64(seed),
+            None => ChaCha12Rng::from_entropy(),
+        };
+        Ok(Self { config, rng })
+    }
+
+    /// Generates a new level where the goal is reachable.
+    ///
+    /// Fails if no solvable level turns up in `MAX_ATTEMPTS` tries, which happens when
+    /// the densities leave too little room to move.
+    pub fn generate(&mut self) -> Result<Level> {
+        for _ in 0..MAX_ATTEMPTS {
+            let level = self.generate_unchecked();
+            if let Some(level) = level {
+                if level.is_solvable() {
+                    return Ok(level);
+                }
+            }
+        }
+        bail!("Could not generate a solvable level in {MAX_ATTEMPTS} attempts, try lowering the densities.")
+    }
+
+    /// Generates a level without checking if it's solvable.
+    /// Returns `None` if there isn't room for the goal and agent.
+    fn generate_unchecked(&mut self) -> Option<Level> {
+        let thresholds = [
+            (COIN_IDX, self.config.coin_density),
+            (PIT_IDX, self.config.pit_density),
+            (WALL_IDX, self.config.wall_density),
+            (BOX_IDX, self.config.box_density),
+        ];
+        let (width, height) = (self.config.width, self.config.height);
+        let mut cells = vec![WALL_IDX + 1; width * height];
+        for y in 1..height - 1 {
+            for x in 1..width - 1 {
+                let mut roll = self.rng.gen::<f32>();
+                let mut cell = 0;
+                for &(idx, density) in &thresholds {
+                    if roll < density {
+                        cell = idx + 1;
+                        break;
+                    }
+                    roll -= density;
+                }
+                cells[y * width + x] = cell;
+            }
+        }
+
+        let empty: Vec<_> = (0..cells.len()).filter(|&i| cells[i] == 0).collect();
+        if empty.len() < 2 {
+            return None;
+        }
+        let goal_i = self.rng.gen_range(0..empty.len());
+        let mut agent_i = self.rng.gen_range(0..empty.len() - 1);
+        if agent_i >= goal_i {
+            agent_i += 1;
+        }
+        let to_pos = |i: usize| (i % width, i / width);
+        Some(Level {
+            width,
+            height,
+            cells,
+            goal_pos: to_pos(empty[goal_i]),
+            agent_pos: to_pos(empty[agent_i]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Level, ParseLevelError> {
+        text.parse()
+    }
+
+    fn error_at(text: &str) -> (usize, usize) {
+        let err = parse(text).unwrap_err();
+        (err.line, err.column)
+    }
+
+    #[test]
+    fn default_level_round_trips() {
+        let text = include_str!("../levels/default.txt");
+        let level = parse(text).unwrap();
+        assert_eq!((level.width, level.height), (6, 6));
+        assert_eq!(level.goal_pos, (4, 1));
+        assert_eq!(level.agent_pos, (1, 4));
+
+        let written = level.to_string();
+        assert_eq!(written, text);
+        assert_eq!(parse(&written).unwrap(), level);
+    }
+
+    #[test]
+    fn ragged_row_is_reported_where_it_ends() {
+        assert_eq!(error_at("*****\n*A G*\n***\n*****\n"), (3, 4));
+    }
+
+    #[test]
+    fn unknown_glyph_is_reported_at_its_cell() {
+        assert_eq!(error_at("*****\n*A G*\n* X *\n*****\n"), (3, 3));
+    }
+
+    #[test]
+    fn missing_goal_is_reported_on_the_last_line() {
+        let err = parse("*****\n*A  *\n*****\n").unwrap_err();
+        assert_eq!((err.line, err.column), (3, 1));
+        assert_eq!(err.message, "missing `G`");
+    }
+
+    #[test]
+    fn border_must_be_walls() {
+        assert_eq!(error_at("*****\n A G*\n*****\n"), (2, 1));
+    }
+
+    #[test]
+    fn walled_in_agent_is_rejected() {
+        assert_eq!(error_at("*****\n*A*G*\n*****\n"), (2, 2));
+    }
+
+    #[test]
+    fn open_path_is_solvable() {
+        assert!(parse("******\n*A @G*\n******\n").unwrap().is_solvable());
+    }
+
+    #[test]
+    fn pit_in_the_only_path_is_unsolvable() {
+        assert!(!parse("******\n*A !G*\n******\n").unwrap().is_solvable());
+    }
+
+    #[test]
+    fn box_pushed_out_of_a_doorway_is_solvable() {
+        // The box must be pushed down twice to clear the doorway.
+        let level = parse("******\n*A   *\n**O***\n*    *\n*  G *\n******\n").unwrap();
+        assert!(level.is_solvable());
+    }
+
+    #[test]
+    fn box_pushed_against_a_wall_is_unsolvable() {
+        // Pushing the box ends with it on the goal, stuck against the border.
+        assert!(!parse("******\n*AO G*\n******\n").unwrap().is_solvable());
+    }
+
+    #[test]
+    fn search_gives_up_on_large_state_spaces() {
+        // An open room with a few boxes has far more states than the search visits,
+        // and the goal is walled off so it never finishes early.
+        let size = 12;
+        let mut cells = vec![0; size * size];
+        for i in 0..size {
+            for pos in [(i, 0), (i, size - 1), (0, i), (size - 1, i)] {
+                cells[pos.1 * size + pos.0] = WALL_IDX + 1;
+            }
+        }
+        for (x, y) in [(size - 3, size - 2), (size - 2, size - 3)] {
+            cells[y * size + x] = WALL_IDX + 1;
+        }
+        for (x, y) in [(3, 3), (5, 4), (4, 7), (7, 5)] {
+            cells[y * size + x] = BOX_IDX + 1;
+        }
+        let level = Level {
+            width: size,
+            height: size,
+            cells,
+            goal_pos: (size - 2, size - 2),
+            agent_pos: (1, 1),
+        };
+        assert!(!level.is_solvable());
+    }
+
+    #[test]
+    fn seeded_generator_repeats_solvable_levels() {
+        let config = LevelGenConfig {
+            seed: Some(7),
+            ..Default::default()
+        };
+        let levels = |config: LevelGenConfig| {
+            let mut generator = LevelGenerator::new(config).unwrap();
+            (0..5)
+                .map(|_| generator.generate().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let first = levels(config.clone());
+        assert_eq!(first, levels(config.clone()));
+        assert!(first.iter().all(Level::is_solvable));
+        assert_ne!(
+            first,
+            levels(LevelGenConfig {
+                seed: Some(8),
+                ..config
+            })
+        );
+    }
+
+    #[test]
+    fn generator_rejects_invalid_configs() {
+        let config = |width, wall_density| LevelGenConfig {
+            width,
+            wall_density,
+            ..Default::default()
+        };
+        assert!(LevelGenerator::new(config(2, 0.1)).is_err());
+        assert!(LevelGenerator::new(config(DEFAULT_GRID_SIZE, 1.5)).is_err());
+        assert!(LevelGenerator::new(config(DEFAULT_GRID_SIZE, 0.9)).is_err());
+    }
+}
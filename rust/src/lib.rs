@@ -1,14 +1,40 @@
-mod dqn;
-mod env;
-mod model;
-mod replay_buffer;
-
-use candle_core::{DType, Device, IndexOp, Module, Result, Tensor};
-use candle_nn::VarBuilder;
-use model::QNet;
+pub mod cartpole;
+pub mod checkpoint;
+pub mod cli;
+pub mod config;
+pub mod dqn;
+pub mod env;
+pub mod environment;
+pub mod evaluate;
+pub mod level;
+pub mod metrics;
+pub mod model;
+pub mod noisy;
+pub mod optim;
+pub mod replay_buffer;
+pub mod saved_model;
+pub mod seeding;
+pub mod sum_tree;
+pub mod tensorboard;
+pub mod trainer;
+
+use candle_core::{DType, Device, IndexOp, Tensor};
+use model::QModel;
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    rngs::StdRng,
+    seq::SliceRandom,
+    Rng, SeedableRng,
+};
 use wasm_bindgen::prelude::*;
 
-use crate::env::{GRID_SIZE, NUM_CHANNELS};
+use crate::{
+    env::{GridEnv, State, NUM_CHANNELS},
+    environment::{ActSpace, Environment, Observation, StepInfo},
+    evaluate::{apply_mask, mask_to_tensor},
+    level::{Level, LevelGenConfig, LevelGenerator, LevelSet, LevelSource},
+    saved_model::read_model,
+};
 
 #[wasm_bindgen]
 extern "C" {
@@ -18,30 +44,334 @@ extern "C" {
 
 #[wasm_bindgen]
 pub struct DQN {
-    net: QNet,
+    net: Box<dyn QModel>,
+    action_count: usize,
+    /// Probability of taking a random allowed action instead of the chosen one.
+    epsilon: f32,
+    /// Samples actions from a softmax over Q-values with this temperature if above 0,
+    /// otherwise takes the best action.
+    temperature: f32,
+    rng: StdRng,
+    /// Logs inputs and outputs to the browser console.
+    debug: bool,
+}
+
+/// An action picked by `DQN::act`, with the Q-values it was picked from.
+#[wasm_bindgen]
+pub struct Action {
+    action: u32,
+    q_values: Vec<f32>,
+}
+
+#[wasm_bindgen]
+impl Action {
+    #[wasm_bindgen(getter)]
+    pub fn action(&self) -> u32 {
+        self.action
+    }
+
+    /// Q-values of every action. Masked actions are `-Infinity`.
+    #[wasm_bindgen(getter)]
+    pub fn q_values(&self) -> Vec<f32> {
+        self.q_values.clone()
+    }
 }
 
 #[wasm_bindgen]
 impl DQN {
-    pub fn load(data: &[u8]) -> Self {
-        let vs =
-            VarBuilder::from_buffered_safetensors(data.to_vec(), DType::F32, &Device::Cpu).unwrap();
-        let net = QNet::new(vs, NUM_CHANNELS, 4).unwrap();
-        Self { net }
+    /// Loads a grid Q network saved by the trainer, rebuilt from the file's metadata.
+    /// Distributional networks act on the expected value of their predictions.
+    pub fn load(data: &[u8]) -> Result<DQN, JsValue> {
+        let (meta, vs) = read_model(data)
+            .map_err(|e| js_error(format!("Could not read the model file: {e:#}")))?;
+        meta.check_env::<GridEnv>().map_err(js_error)?;
+        let channels = meta.obs_shape.first().copied().unwrap_or(0);
+        if channels != NUM_CHANNELS {
+            return Err(js_error(format!(
+                "The model takes {channels} input channels, but grid observations have {NUM_CHANNELS}."
+            )));
+        }
+        let action_count = meta.action_count;
+        let net = meta.build(vs).map_err(js_error)?;
+        Ok(Self {
+            net,
+            action_count,
+            epsilon: 0.,
+            temperature: 0.,
+            rng: StdRng::from_entropy(),
+            debug: false,
+        })
     }
 
-    pub fn eval_state(&self, state: &[u8]) -> Vec<f32> {
-        move || -> Result<_> {
-            let state = Tensor::new(state, &Device::Cpu)?
-                .reshape(&[NUM_CHANNELS, GRID_SIZE, GRID_SIZE])?
-                .to_dtype(DType::F32)?
-                .unsqueeze(0)?;
-            for i in 0..6 {
-                log(&format!("{}", state.i(0)?.i(i)?));
-            }
+    /// Logs each state's channels, Q-values and picked actions to the console.
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    pub fn set_epsilon(&mut self, epsilon: f32) {
+        self.epsilon = epsilon;
+    }
+
+    pub fn set_temperature(&mut self, temperature: f32) {
+        self.temperature = temperature;
+    }
+
+    /// Makes random action choices repeatable.
+    pub fn set_seed(&mut self, seed: u32) {
+        self.rng = StdRng::seed_from_u64(seed as u64);
+    }
+
+    /// Returns the Q values of a `width` by `height` grid state.
+    pub fn eval_state(
+        &self,
+        state: &[u8],
+        width: usize,
+        height: usize,
+    ) -> Result<Vec<f32>, JsValue> {
+        let state = self.state_tensor(state, width, height)?;
+        let q_vals = || -> candle_core::Result<Vec<f32>> {
+            self.net.forward(&state)?.squeeze(0)?.to_vec1()
+        }()
+        .map_err(js_error)?;
+        if self.debug {
+            log(&format!("Q-values: {q_vals:?}"));
+        }
+        Ok(q_vals)
+    }
+
+    /// Picks an action for a `width` by `height` grid state, never one where `mask`
+    /// is 1, using the same masking as training.
+    pub fn act(
+        &mut self,
+        state: &[u8],
+        width: usize,
+        height: usize,
+        mask: &[u8],
+    ) -> Result<Action, JsValue> {
+        if mask.len() != self.action_count {
+            return Err(js_error(format!(
+                "Expected a mask with {} entries, found {}.",
+                self.action_count,
+                mask.len()
+            )));
+        }
+        let mask: Vec<_> = mask.iter().map(|&m| m > 0).collect();
+        let state = self.state_tensor(state, width, height)?;
+        let q_values = || -> candle_core::Result<Vec<f32>> {
             let q_vals = self.net.forward(&state)?.squeeze(0)?;
-            q_vals.to_vec1()
+            apply_mask(&q_vals, &mask_to_tensor(&mask)?.squeeze(0)?)?.to_vec1()
+        }()
+        .map_err(js_error)?;
+        let q_values: Vec<_> = q_values
+            .into_iter()
+            .zip(&mask)
+            .map(|(q, &masked)| if masked { f32::NEG_INFINITY } else { q })
+            .collect();
+        let action = self.select(&q_values, &mask);
+        if self.debug {
+            log(&format!("Q-values: {q_values:?}, action: {action}"));
+        }
+        Ok(Action { action, q_values })
+    }
+
+    /// Picks an action for the current state of `env`.
+    pub fn act_env(&mut self, env: &WasmGridEnv) -> Result<Action, JsValue> {
+        self.act(
+            &env.observation(),
+            env.width(),
+            env.height(),
+            &env.action_mask(),
+        )
+    }
+
+    /// Picks among allowed actions, by epsilon-greedy and softmax sampling if enabled.
+    fn select(&mut self, q_values: &[f32], mask: &[bool]) -> u32 {
+        let mut allowed: Vec<_> = (0..q_values.len()).filter(|&a| !mask[a]).collect();
+        // Fall back to every action so there's always something to pick.
+        if allowed.is_empty() {
+            allowed = (0..q_values.len()).collect();
+        }
+        if self.rng.gen::<f32>() < self.epsilon {
+            return *allowed.choose(&mut self.rng).unwrap() as u32;
+        }
+        let best = allowed
+            .iter()
+            .copied()
+            .max_by(|&a, &b| q_values[a].total_cmp(&q_values[b]))
+            .unwrap();
+        if self.temperature <= 0. {
+            return best as u32;
+        }
+        // Subtract the best Q-value so the exponentials don't overflow.
+        let weights: Vec<_> = allowed
+            .iter()
+            .map(|&a| ((q_values[a] - q_values[best]) / self.temperature).exp())
+            .collect();
+        match WeightedIndex::new(&weights) {
+            Ok(dist) => allowed[dist.sample(&mut self.rng)] as u32,
+            Err(_) => best as u32,
+        }
+    }
+
+    /// Turns a flat grid state into a batch of one observation.
+    fn state_tensor(&self, state: &[u8], width: usize, height: usize) -> Result<Tensor, JsValue> {
+        let expected = NUM_CHANNELS * width * height;
+        if state.len() != expected {
+            return Err(js_error(format!(
+                "Expected a state of {NUM_CHANNELS} channels of {width} by {height} cells, \
+                 {expected} values in total, found {}.",
+                state.len()
+            )));
+        }
+        let state = || -> candle_core::Result<Tensor> {
+            Tensor::new(state, &Device::Cpu)?
+                .reshape(&[NUM_CHANNELS, height, width])?
+                .to_dtype(DType::F32)?
+                .unsqueeze(0)
         }()
-        .unwrap()
+        .map_err(js_error)?;
+        if self.debug {
+            for i in 0..NUM_CHANNELS {
+                let channel = state.i(0).and_then(|s| s.i(i)).map_err(js_error)?;
+                log(&format!("Channel {i}:\n{channel}"));
+            }
+        }
+        Ok(state)
+    }
+}
+
+/// Cell value of the goal in `WasmGridEnv::cells`. Other cells use the level encoding.
+const GOAL_CELL: u8 = 5;
+
+fn js_error(e: impl std::fmt::Display) -> JsValue {
+    JsValue::from_str(&format!("{e:#}"))
+}
+
+/// `GridEnv` for the browser, so it runs the same dynamics the agent was trained on.
+#[wasm_bindgen(js_name = GridEnv)]
+pub struct WasmGridEnv {
+    env: GridEnv,
+    obs: State,
+    info: StepInfo,
+    reward: f32,
+}
+
+#[wasm_bindgen(js_class = GridEnv)]
+impl WasmGridEnv {
+    /// Creates an environment that plays generated levels.
+    #[wasm_bindgen(constructor)]
+    pub fn new(seed: Option<u32>) -> Result<WasmGridEnv, JsValue> {
+        let generator = LevelGenerator::new(LevelGenConfig {
+            seed: seed.map(u64::from),
+            ..Default::default()
+        })
+        .map_err(js_error)?;
+        Self::with_levels(LevelSource::Generator(generator))
+    }
+
+    /// Creates an environment that always plays the level in `text`, written like
+    /// level files.
+    pub fn from_level(text: &str) -> Result<WasmGridEnv, JsValue> {
+        let level = text.parse::<Level>().map_err(js_error)?;
+        let levels = LevelSet::new(vec![level], None).map_err(js_error)?;
+        Self::with_levels(LevelSource::Set(levels))
+    }
+
+    fn with_levels(levels: LevelSource) -> Result<WasmGridEnv, JsValue> {
+        let mut env = GridEnv::with_levels(levels);
+        let (obs, info) = env.reset().map_err(js_error)?;
+        Ok(Self {
+            env,
+            obs,
+            info,
+            reward: 0.,
+        })
+    }
+
+    /// Starts a new episode.
+    pub fn reset(&mut self) -> Result<(), JsValue> {
+        (self.obs, self.info) = self.env.reset().map_err(js_error)?;
+        self.reward = 0.;
+        Ok(())
+    }
+
+    /// Moves the agent and returns the reward. Actions are left, right, up and down.
+    pub fn step(&mut self, action: u32) -> Result<f32, JsValue> {
+        if self.info.done() {
+            return Err(js_error("The episode is over, call `reset` first."));
+        }
+        let ActSpace::Discrete(action_count) = self.env.action_space();
+        if action as usize >= action_count {
+            return Err(js_error(format!(
+                "Invalid action {action}, expected less than {action_count}."
+            )));
+        }
+        (self.obs, self.reward, self.info) = self.env.step(action);
+        Ok(self.reward)
+    }
+
+    pub fn width(&self) -> usize {
+        self.env.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.env.height
+    }
+
+    /// Returns the observation the agent sees, a stack of `NUM_CHANNELS` row major
+    /// planes with 1 where a layer is set.
+    pub fn observation(&self) -> Vec<u8> {
+        self.obs.to_vec().into_iter().map(|v| v as u8).collect()
+    }
+
+    /// Returns 1 for each action that is not allowed, e.g. moving into a wall.
+    pub fn action_mask(&self) -> Vec<u8> {
+        self.info.action_mask.iter().map(|&m| m as u8).collect()
+    }
+
+    /// Returns the contents of each cell, row major. 0 is empty, 1 a coin, 2 a pit,
+    /// 3 a wall, 4 a box and 5 the goal. The agent is at `agent_x`, `agent_y`.
+    pub fn cells(&self) -> Vec<u8> {
+        let level = self.env.to_level();
+        let mut cells: Vec<_> = level.cells.iter().map(|&c| c as u8).collect();
+        let (x, y) = level.goal_pos;
+        cells[y * level.width + x] = GOAL_CELL;
+        cells
+    }
+
+    pub fn agent_x(&self) -> usize {
+        self.env.agent_pos.0
+    }
+
+    pub fn agent_y(&self) -> usize {
+        self.env.agent_pos.1
+    }
+
+    /// Returns the reward of the last step.
+    pub fn reward(&self) -> f32 {
+        self.reward
+    }
+
+    pub fn terminated(&self) -> bool {
+        self.info.terminated
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.info.truncated
+    }
+
+    /// Returns true if the episode is over for any reason.
+    pub fn done(&self) -> bool {
+        self.info.done()
+    }
+
+    /// Returns true if the agent reached the goal.
+    pub fn is_success(&self) -> bool {
+        self.env.is_success()
+    }
+
+    /// Returns the current state written like a level file.
+    pub fn level_text(&self) -> String {
+        self.env.to_level().to_string()
     }
 }
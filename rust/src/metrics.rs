@@ -0,0 +1,166 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// File format metrics are written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsFormat {
+    /// Don't record metrics.
+    None,
+    /// Comma separated values with a header row. Missing values are left empty.
+    Csv,
+    /// One JSON object per line. Missing values are `null`.
+    Jsonl,
+}
+
+impl MetricsFormat {
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            MetricsFormat::None => None,
+            MetricsFormat::Csv => Some("csv"),
+            MetricsFormat::Jsonl => Some("jsonl"),
+        }
+    }
+}
+
+/// Metrics recorded after a training iteration.
+/// Values that weren't measured on an iteration are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct IterationMetrics {
+    pub iteration: usize,
+    /// Q loss summed over the iteration's training passes.
+    pub loss: Option<f32>,
+    pub mean_abs_td: Option<f32>,
+    pub q_mean: Option<f32>,
+    pub q_min: Option<f32>,
+    pub q_max: Option<f32>,
+    /// Probability of taking a random action while collecting experience.
+    pub epsilon: f32,
+    /// Fraction of the replay buffer that holds transitions.
+    pub buffer_fill: f32,
+    /// Mean return of the evaluation episodes.
+    pub eval_return: Option<f32>,
+    /// Mean length of the evaluation episodes.
+    pub eval_length: Option<f32>,
+}
+
+impl IterationMetrics {
+    const COLUMNS: [&'static str; 10] = [
+        "iteration",
+        "loss",
+        "mean_abs_td",
+        "q_mean",
+        "q_min",
+        "q_max",
+        "epsilon",
+        "buffer_fill",
+        "eval_return",
+        "eval_length",
+    ];
+
+    fn csv_header() -> String {
+        Self::COLUMNS.join(",")
+    }
+
+    fn csv_row(&self) -> String {
+        let opt = |v: Option<f32>| v.map(|v| v.to_string()).unwrap_or_default();
+        [
+            self.iteration.to_string(),
+            opt(self.loss),
+            opt(self.mean_abs_td),
+            opt(self.q_mean),
+            opt(self.q_min),
+            opt(self.q_max),
+            self.epsilon.to_string(),
+            self.buffer_fill.to_string(),
+            opt(self.eval_return),
+            opt(self.eval_length),
+        ]
+        .join(",")
+    }
+}
+
+/// Appends a row of metrics per training iteration to a file.
+pub struct MetricsLogger {
+    path: PathBuf,
+    format: MetricsFormat,
+    writer: BufWriter<File>,
+}
+
+impl MetricsLogger {
+    /// Starts a new metrics file, replacing any existing one.
+    pub fn create(path: impl AsRef<Path>, format: MetricsFormat) -> Result<Self> {
+        Self::open(path.as_ref(), format, Vec::new())
+    }
+
+    /// Continues a metrics file from `iteration`. Rows from later iterations, which
+    /// were logged after the checkpoint being resumed from, are dropped.
+    pub fn resume(path: impl AsRef<Path>, format: MetricsFormat, iteration: usize) -> Result<Self> {
+        let path = path.as_ref();
+        let mut kept = Vec::new();
+        if path.exists() {
+            let file = File::open(path)
+                .with_context(|| format!("Could not read metrics {}", path.display()))?;
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                let row_iteration = match format {
+                    MetricsFormat::Csv => line.split(',').next().and_then(|i| i.parse().ok()),
+                    MetricsFormat::Jsonl => serde_json::from_str::<serde_json::Value>(&line)
+                        .ok()
+                        .and_then(|row| row["iteration"].as_u64())
+                        .map(|i| i as usize),
+                    MetricsFormat::None => None,
+                };
+                if row_iteration.is_some_and(|i| i < iteration) {
+                    kept.push(line);
+                }
+            }
+        }
+        Self::open(path, format, kept)
+    }
+
+    fn open(path: &Path, format: MetricsFormat, rows: Vec<String>) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Could not create metrics file {}", path.display()))?;
+        let mut logger = Self {
+            path: path.to_path_buf(),
+            format,
+            writer: BufWriter::new(file),
+        };
+        if format == MetricsFormat::Csv {
+            logger.write_line(&IterationMetrics::csv_header())?;
+        }
+        for row in rows {
+            logger.write_line(&row)?;
+        }
+        logger.flush()?;
+        Ok(logger)
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        writeln!(self.writer, "{line}")
+            .with_context(|| format!("Could not write metrics to {}", self.path.display()))
+    }
+
+    pub fn log(&mut self, metrics: &IterationMetrics) -> Result<()> {
+        let line = match self.format {
+            MetricsFormat::None => return Ok(()),
+            MetricsFormat::Csv => metrics.csv_row(),
+            MetricsFormat::Jsonl => serde_json::to_string(metrics)?,
+        };
+        self.write_line(&line)
+    }
+
+    /// Writes buffered rows to disk.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer
+            .flush()
+            .with_context(|| format!("Could not write metrics to {}", self.path.display()))
+    }
+}
@@ -1,10 +1,218 @@
-use anyhow::Result;
-use candle_core::{Module, Tensor, D};
+use anyhow::{bail, Result};
+use candle_core::{Device, Module, Tensor, D};
 use nn::VarBuilder;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::noisy::{noisy_linear, NoisyLinear};
 
-use crate::env::GRID_SIZE;
 use candle_nn as nn;
 
+/// Which network a model uses, saved alongside its weights so it can be rebuilt.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Architecture {
+    /// `QNet`, for stacks of grid planes.
+    QNet(QNetConfig),
+    /// `MlpQNet`, for flat vectors.
+    Mlp(MlpConfig),
+}
+
+impl Architecture {
+    /// Creates the network for observations of `obs_shape`, predicting `distribution`.
+    pub fn build(
+        &self,
+        vs: VarBuilder,
+        obs_shape: &[usize],
+        action_count: usize,
+        distribution: &ValueDistribution,
+    ) -> Result<Box<dyn QModel>> {
+        Ok(match self {
+            Architecture::QNet(config) => {
+                if obs_shape.len() != 3 {
+                    bail!("`QNet` expects observations of shape (channels, height, width), got {obs_shape:?}.");
+                }
+                Box::new(QNet::with_config(
+                    vs,
+                    obs_shape[0],
+                    action_count,
+                    config,
+                    distribution,
+                )?)
+            }
+            Architecture::Mlp(config) => Box::new(MlpQNet::with_config(
+                vs,
+                obs_shape.iter().product(),
+                action_count,
+                config,
+                distribution,
+            )?),
+        })
+    }
+}
+
+/// What a network predicts about the return of each action.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum ValueDistribution {
+    /// Only its expected value, as in DQN.
+    #[default]
+    Expected,
+    /// The probability of each of `atoms` evenly spaced returns from `v_min` to `v_max`,
+    /// as in C51. Returns outside the range are clipped to it.
+    C51 {
+        #[serde(default = "default_atoms")]
+        atoms: usize,
+        #[serde(default = "default_v_min")]
+        v_min: f64,
+        #[serde(default = "default_v_max")]
+        v_max: f64,
+    },
+    /// The returns at `quantiles` evenly spaced fractions, as in QR-DQN, trained with a
+    /// quantile Huber loss that is quadratic below `kappa`.
+    ///
+    /// Actions are picked by the mean of the lowest `cvar` fraction of quantiles, so
+    /// values below 1 prefer actions whose bad outcomes are less bad.
+    Qr {
+        #[serde(default = "default_quantiles")]
+        quantiles: usize,
+        #[serde(default = "default_kappa")]
+        kappa: f64,
+        #[serde(default = "default_cvar")]
+        cvar: f64,
+    },
+    /// The returns at fractions given as input, embedded with `embedding_dim` cosines,
+    /// as in IQN. `samples` fractions are drawn for each prediction and target while
+    /// training, and actions are picked by the mean of `policy_samples` evenly spaced
+    /// fractions of the lowest `cvar` of returns.
+    Iqn {
+        #[serde(default = "default_samples")]
+        samples: usize,
+        #[serde(default = "default_policy_samples")]
+        policy_samples: usize,
+        #[serde(default = "default_embedding_dim")]
+        embedding_dim: usize,
+        #[serde(default = "default_kappa")]
+        kappa: f64,
+        #[serde(default = "default_cvar")]
+        cvar: f64,
+    },
+}
+
+fn default_atoms() -> usize {
+    51
+}
+
+fn default_v_min() -> f64 {
+    -10.
+}
+
+fn default_v_max() -> f64 {
+    10.
+}
+
+fn default_quantiles() -> usize {
+    32
+}
+
+fn default_samples() -> usize {
+    8
+}
+
+fn default_policy_samples() -> usize {
+    32
+}
+
+fn default_embedding_dim() -> usize {
+    64
+}
+
+fn default_kappa() -> f64 {
+    1.
+}
+
+fn default_cvar() -> f64 {
+    1.
+}
+
+impl ValueDistribution {
+    /// Number of values predicted for each action, without fractions given for IQN.
+    pub fn outputs(&self) -> usize {
+        match self {
+            Self::Expected => 1,
+            Self::C51 { atoms, .. } => *atoms,
+            Self::Qr { quantiles, .. } => *quantiles,
+            Self::Iqn { policy_samples, .. } => *policy_samples,
+        }
+    }
+
+    /// Returns the values actions are picked by from outputs of shape (batch, actions,
+    /// outputs). These are the expected returns unless `cvar` is below 1.
+    pub fn expected(&self, dist: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            Self::Expected => dist.squeeze(D::Minus1),
+            &Self::C51 {
+                atoms,
+                v_min,
+                v_max,
+            } => {
+                let support = Tensor::new(support(atoms, v_min, v_max), dist.device())?;
+                nn::ops::softmax(dist, D::Minus1)?
+                    .broadcast_mul(&support)?
+                    .sum(D::Minus1)
+            }
+            // Quantiles are ordered by fraction, so the lowest come first.
+            &Self::Qr { cvar, .. } => {
+                let quantiles = dist.dim(D::Minus1)?;
+                let lowest = ((quantiles as f64 * cvar).ceil() as usize).clamp(1, quantiles);
+                dist.narrow(D::Minus1, 0, lowest)?.mean(D::Minus1)
+            }
+            // The policy fractions already cover only the lowest returns.
+            Self::Iqn { .. } => dist.mean(D::Minus1),
+        }
+    }
+}
+
+/// Returns the returns C51 atoms stand for.
+pub fn support(atoms: usize, v_min: f64, v_max: f64) -> Vec<f32> {
+    let delta = (v_max - v_min) / (atoms - 1) as f64;
+    (0..atoms)
+        .map(|i| (v_min + i as f64 * delta) as f32)
+        .collect()
+}
+
+/// Returns the middle of `n` equal intervals of [0, 1], the fractions QR-DQN quantiles
+/// stand for.
+pub fn quantile_midpoints(n: usize) -> Vec<f32> {
+    (0..n)
+        .map(|i| (2 * i + 1) as f32 / (2 * n) as f32)
+        .collect()
+}
+
+/// A Q network that knows its architecture.
+///
+/// `forward` returns Q-values of shape (batch, actions) whatever the network predicts,
+/// as given by `ValueDistribution::expected`, so actions are picked and masked the same
+/// way for every network.
+pub trait QModel: Module {
+    fn architecture(&self) -> Architecture;
+    fn distribution(&self) -> &ValueDistribution;
+    /// Returns what the network predicts for each action, with shape (batch, actions,
+    /// outputs), e.g. atom logits for C51.
+    ///
+    /// IQN networks predict the returns at fractions `taus` of shape (batch, samples),
+    /// or at their policy fractions if not given. Other networks ignore `taus`.
+    fn forward_dist(&self, xs: &Tensor, taus: Option<&Tensor>) -> candle_core::Result<Tensor>;
+    /// Draws new noise for the network's `NoisyLinear` layers. Networks without them
+    /// draw nothing from `rng`.
+    fn resample_noise(&self, rng: &mut dyn RngCore) -> candle_core::Result<()>;
+    /// Makes `NoisyLinear` layers use their mean weights until noise is resampled,
+    /// e.g. to evaluate the network.
+    fn remove_noise(&self);
+    /// Returns true if the network has `NoisyLinear` layers, and so explores by itself.
+    fn is_noisy(&self) -> bool;
+}
+
 /// A skip connection.
 struct Skip {
     module: nn::Sequential,
@@ -45,22 +253,329 @@ fn skip(features: usize, vs: VarBuilder) -> candle_core::Result<Skip> {
     Ok(Skip { module })
 }
 
+/// How `QNet` reduces the grid to one feature vector, so any grid size works.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pooling {
+    Max,
+    Mean,
+}
+
+/// Shape of a `QNet`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QNetConfig {
+    /// Number of features of every convolution.
+    pub conv_width: usize,
+    /// Number of residual blocks.
+    pub skip_blocks: usize,
+    /// Number of times the residual blocks are applied, reusing their weights.
+    pub recurrence: usize,
+    /// Whether to split the head into value and advantage streams.
+    pub dueling: bool,
+    /// Sizes of the hidden layers of the head, or of each stream if dueling.
+    pub head_hidden: Vec<usize>,
+    pub pooling: Pooling,
+    /// Whether the head's layers are `NoisyLinear`, so the network explores by itself.
+    pub noisy: bool,
+}
+
+impl Default for QNetConfig {
+    fn default() -> Self {
+        Self {
+            conv_width: 32,
+            skip_blocks: 2,
+            recurrence: 1,
+            dueling: true,
+            head_hidden: vec![32],
+            pooling: Pooling::Max,
+            noisy: false,
+        }
+    }
+}
+
+/// A linear layer of a head.
+enum HeadLinear {
+    Plain(nn::Linear),
+    Noisy(NoisyLinear),
+}
+
+impl Module for HeadLinear {
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            HeadLinear::Plain(linear) => linear.forward(xs),
+            HeadLinear::Noisy(linear) => linear.forward(xs),
+        }
+    }
+}
+
+/// Linear layers with ReLUs in between.
+struct LinearStack {
+    layers: Vec<HeadLinear>,
+}
+
+impl LinearStack {
+    /// Names layers `{prefix}ln{first}`, `{prefix}ln{first + 1}`...
+    fn new(
+        in_size: usize,
+        hidden: &[usize],
+        out_size: usize,
+        vs: &VarBuilder,
+        shape: &StreamShape,
+        prefix: &str,
+    ) -> Result<Self> {
+        let sizes = std::iter::once(in_size)
+            .chain(hidden.iter().copied())
+            .zip(hidden.iter().copied().chain(std::iter::once(out_size)));
+        let layers = sizes
+            .enumerate()
+            .map(|(i, (size_in, size_out))| {
+                let vs = vs.pp(format!("{prefix}ln{}", shape.first + i));
+                Ok(if shape.noisy {
+                    HeadLinear::Noisy(noisy_linear(size_in, size_out, vs)?)
+                } else {
+                    HeadLinear::Plain(nn::linear(size_in, size_out, vs)?)
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { layers })
+    }
+
+    fn noisy_layers(&self) -> impl Iterator<Item = &NoisyLinear> {
+        self.layers.iter().filter_map(|layer| match layer {
+            HeadLinear::Noisy(linear) => Some(linear),
+            HeadLinear::Plain(_) => None,
+        })
+    }
+}
+
+impl Module for LinearStack {
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        let mut xs = xs.clone();
+        for (i, layer) in self.layers.iter().enumerate() {
+            if i > 0 {
+                xs = xs.relu()?;
+            }
+            xs = layer.forward(&xs)?;
+        }
+        Ok(xs)
+    }
+}
+
+/// The layers of a head's streams and how they're named.
+struct StreamShape<'a> {
+    /// Sizes of the hidden layers of each stream.
+    hidden: &'a [usize],
+    /// Whether to split the head into value and advantage streams, prefixed with `a_`
+    /// and `v_`.
+    dueling: bool,
+    /// Whether layers are `NoisyLinear`.
+    noisy: bool,
+    /// Prefix of the layers if not dueling.
+    prefix: &'a str,
+    /// Number of the first layer of each stream.
+    first: usize,
+}
+
+/// Embeds IQN fractions with cosines of increasing frequency, scaled to the size of
+/// the features they're multiplied with.
+struct TauEmbedding {
+    linear: nn::Linear,
+    dim: usize,
+    /// Evenly spaced fractions of the lowest `cvar` of returns, used when none are
+    /// given so picking actions is deterministic.
+    policy_taus: Vec<f32>,
+}
+
+impl TauEmbedding {
+    fn policy_taus(&self, batch: usize, device: &Device) -> candle_core::Result<Tensor> {
+        Tensor::new(self.policy_taus.as_slice(), device)?
+            .unsqueeze(0)?
+            .repeat(&[batch, 1])
+    }
+}
+
+impl Module for TauEmbedding {
+    /// Turns fractions of shape (batch, samples) into (batch, samples, features).
+    fn forward(&self, taus: &Tensor) -> candle_core::Result<Tensor> {
+        let frequencies: Vec<_> = (0..self.dim)
+            .map(|i| i as f32 * std::f32::consts::PI)
+            .collect();
+        let frequencies = Tensor::new(frequencies, taus.device())?;
+        let cosines = taus.unsqueeze(2)?.broadcast_mul(&frequencies)?.cos()?;
+        self.linear.forward(&cosines)?.relu()
+    }
+}
+
+/// The layers after a network's trunk, which predict each action's value from a
+/// feature vector.
+struct Head {
+    /// The advantage stream if dueling, otherwise the outputs.
+    advantage: LinearStack,
+    /// Only used if dueling.
+    value: Option<LinearStack>,
+    /// Only used by IQN.
+    tau_embedding: Option<TauEmbedding>,
+    action_count: usize,
+    distribution: ValueDistribution,
+}
+
+impl Head {
+    fn new(
+        vs: &VarBuilder,
+        in_size: usize,
+        action_count: usize,
+        distribution: &ValueDistribution,
+        shape: StreamShape,
+    ) -> Result<Self> {
+        // IQN runs the streams once per fraction instead of predicting every output.
+        let (outputs, tau_embedding) = match *distribution {
+            ValueDistribution::Iqn {
+                policy_samples,
+                embedding_dim,
+                cvar,
+                ..
+            } => (
+                1,
+                Some(TauEmbedding {
+                    linear: nn::linear(embedding_dim, in_size, vs.pp("tau_embed"))?,
+                    dim: embedding_dim,
+                    policy_taus: quantile_midpoints(policy_samples)
+                        .into_iter()
+                        .map(|tau| tau * cvar as f32)
+                        .collect(),
+                }),
+            ),
+            _ => (distribution.outputs(), None),
+        };
+        let stream = |out_size, prefix| {
+            LinearStack::new(in_size, shape.hidden, out_size, vs, &shape, prefix)
+        };
+        let (advantage, value) = if shape.dueling {
+            (
+                stream(action_count * outputs, "a_")?,
+                Some(stream(outputs, "v_")?),
+            )
+        } else {
+            (stream(action_count * outputs, shape.prefix)?, None)
+        };
+        Ok(Self {
+            advantage,
+            value,
+            tau_embedding,
+            action_count,
+            distribution: distribution.clone(),
+        })
+    }
+
+    /// Returns outputs of shape (batch, actions, outputs). Dueling streams are combined
+    /// separately for each output.
+    fn forward_dist(&self, xs: &Tensor, taus: Option<&Tensor>) -> candle_core::Result<Tensor> {
+        let batch = xs.dim(0)?;
+        let (advantage, value) = match &self.tau_embedding {
+            Some(embedding) => {
+                let taus = match taus {
+                    Some(taus) => taus.clone(),
+                    None => embedding.policy_taus(batch, xs.device())?,
+                };
+                let samples = taus.dim(1)?;
+                let xs = xs
+                    .unsqueeze(1)?
+                    .broadcast_mul(&embedding.forward(&taus)?)?
+                    .reshape((batch * samples, ()))?;
+                // Move the fractions after the actions.
+                let split = |out: Tensor, size: usize| {
+                    out.reshape((batch, samples, size))?
+                        .transpose(1, 2)?
+                        .contiguous()
+                };
+                let advantage = split(self.advantage.forward(&xs)?, self.action_count)?;
+                let value = match &self.value {
+                    Some(value) => Some(split(value.forward(&xs)?, 1)?),
+                    None => None,
+                };
+                (advantage, value)
+            }
+            None => {
+                let outputs = self.distribution.outputs();
+                let advantage =
+                    self.advantage
+                        .forward(xs)?
+                        .reshape((batch, self.action_count, outputs))?;
+                let value = match &self.value {
+                    Some(value) => Some(value.forward(xs)?.reshape((batch, 1, outputs))?),
+                    None => None,
+                };
+                (advantage, value)
+            }
+        };
+        let Some(value) = value else {
+            return Ok(advantage);
+        };
+        value
+            .broadcast_add(&advantage)?
+            .broadcast_sub(&advantage.mean_keepdim(1)?)
+    }
+
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        self.distribution.expected(&self.forward_dist(xs, None)?)
+    }
+
+    fn noisy_layers(&self) -> impl Iterator<Item = &NoisyLinear> {
+        self.advantage
+            .noisy_layers()
+            .chain(self.value.iter().flat_map(|value| value.noisy_layers()))
+    }
+
+    fn resample_noise(&self, rng: &mut dyn RngCore) -> candle_core::Result<()> {
+        for layer in self.noisy_layers() {
+            layer.resample(rng)?;
+        }
+        Ok(())
+    }
+
+    fn remove_noise(&self) {
+        for layer in self.noisy_layers() {
+            layer.remove_noise();
+        }
+    }
+
+    fn is_noisy(&self) -> bool {
+        self.noisy_layers().next().is_some()
+    }
+}
+
 pub struct QNet {
     net: nn::sequential::Sequential,
     rep_net: nn::sequential::Sequential,
-    advantage: nn::sequential::Sequential,
-    value: nn::sequential::Sequential,
-    action_count: usize,
+    head: Head,
     out_net: nn::Conv2d,
+    config: QNetConfig,
 }
 
 impl QNet {
     pub fn new(vs: VarBuilder, in_channels: usize, action_count: usize) -> Result<Self> {
+        Self::with_config(
+            vs,
+            in_channels,
+            action_count,
+            &QNetConfig::default(),
+            &ValueDistribution::Expected,
+        )
+    }
+
+    pub fn with_config(
+        vs: VarBuilder,
+        in_channels: usize,
+        action_count: usize,
+        config: &QNetConfig,
+        distribution: &ValueDistribution,
+    ) -> Result<Self> {
         let conv_conf = nn::Conv2dConfig {
             padding: 1,
             ..Default::default()
         };
-        let conv_features = 32;
+        let conv_features = config.conv_width;
         let net = nn::seq().add(nn::conv2d(
             in_channels,
             conv_features,
@@ -68,44 +583,224 @@ impl QNet {
             conv_conf,
             vs.pp("conv1"),
         )?);
-        let rep_net = nn::seq()
-            .add(skip(conv_features, vs.pp("conv2"))?)
-            .add(skip(conv_features, vs.pp("conv3"))?);
-        let out_net = nn::conv2d(conv_features, 32, 3, Default::default(), vs.pp("conv_out"))?;
-        let advantage = nn::seq()
-            .add(nn::linear(32, 32, vs.pp("a_ln1"))?)
-            .add(nn::Activation::Relu)
-            .add(nn::linear(32, action_count, vs.pp("a_ln2"))?);
-        let value = nn::seq()
-            .add(nn::linear(32, 32, vs.pp("v_ln1"))?)
-            .add(nn::Activation::Relu)
-            .add(nn::linear(32, 1, vs.pp("v_ln2"))?);
+        let mut rep_net = nn::seq();
+        for i in 0..config.skip_blocks {
+            rep_net = rep_net.add(skip(conv_features, vs.pp(format!("conv{}", i + 2)))?);
+        }
+        let out_net = nn::conv2d(
+            conv_features,
+            conv_features,
+            3,
+            Default::default(),
+            vs.pp("conv_out"),
+        )?;
+        let head = Head::new(
+            &vs,
+            conv_features,
+            action_count,
+            distribution,
+            StreamShape {
+                hidden: &config.head_hidden,
+                dueling: config.dueling,
+                noisy: config.noisy,
+                prefix: "q_",
+                first: 1,
+            },
+        )?;
         Ok(Self {
             net,
             rep_net,
-            advantage,
-            value,
-            action_count,
+            head,
             out_net,
+            config: config.clone(),
         })
     }
+
+    /// Returns the pooled features of the convolutional trunk.
+    fn features(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        let mut xs = self.net.forward(xs)?;
+        for _ in 0..self.config.recurrence {
+            xs = self.rep_net.forward(&xs)?;
+        }
+        // Global pooling, so any grid size works.
+        let xs = self.out_net.forward(&xs)?;
+        match self.config.pooling {
+            Pooling::Max => xs.max(D::Minus1)?.max(D::Minus1),
+            Pooling::Mean => xs.mean(D::Minus1)?.mean(D::Minus1),
+        }
+    }
+}
+
+impl QModel for QNet {
+    fn architecture(&self) -> Architecture {
+        Architecture::QNet(self.config.clone())
+    }
+
+    fn distribution(&self) -> &ValueDistribution {
+        &self.head.distribution
+    }
+
+    fn forward_dist(&self, xs: &Tensor, taus: Option<&Tensor>) -> candle_core::Result<Tensor> {
+        self.head.forward_dist(&self.features(xs)?, taus)
+    }
+
+    fn resample_noise(&self, rng: &mut dyn RngCore) -> candle_core::Result<()> {
+        self.head.resample_noise(rng)
+    }
+
+    fn remove_noise(&self) {
+        self.head.remove_noise()
+    }
+
+    fn is_noisy(&self) -> bool {
+        self.head.is_noisy()
+    }
 }
 
 impl Module for QNet {
     fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
-        let mut xs = self.net.forward(xs)?;
-        for _ in 0..1 {
-            xs = self.rep_net.forward(&xs)?;
+        self.head.forward(&self.features(xs)?)
+    }
+}
+
+/// Shape of an `MlpQNet`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MlpConfig {
+    /// Sizes of the hidden layers before the head.
+    pub hidden: Vec<usize>,
+    /// Whether to split the last layer into value and advantage streams.
+    pub dueling: bool,
+    /// Whether the last layer is `NoisyLinear`, so the network explores by itself.
+    pub noisy: bool,
+}
+
+impl Default for MlpConfig {
+    fn default() -> Self {
+        Self {
+            hidden: vec![64, 64],
+            dueling: false,
+            noisy: false,
+        }
+    }
+}
+
+/// Q network for flat vector observations, such as those from cartpole.
+pub struct MlpQNet {
+    net: nn::sequential::Sequential,
+    head: Head,
+    config: MlpConfig,
+}
+
+impl MlpQNet {
+    pub fn new(vs: VarBuilder, obs_size: usize, action_count: usize) -> Result<Self> {
+        Self::with_config(
+            vs,
+            obs_size,
+            action_count,
+            &MlpConfig::default(),
+            &ValueDistribution::Expected,
+        )
+    }
+
+    pub fn with_config(
+        vs: VarBuilder,
+        obs_size: usize,
+        action_count: usize,
+        config: &MlpConfig,
+        distribution: &ValueDistribution,
+    ) -> Result<Self> {
+        let mut net = nn::seq();
+        let mut size = obs_size;
+        for (i, &hidden_size) in config.hidden.iter().enumerate() {
+            net = net
+                .add(nn::linear(
+                    size,
+                    hidden_size,
+                    vs.pp(format!("ln{}", i + 1)),
+                )?)
+                .add(nn::Activation::Relu);
+            size = hidden_size;
         }
-        let xs = self
-            .out_net
-            .forward(&xs)?
-            .max_pool2d(GRID_SIZE - 2)?
-            .squeeze(D::Minus1)?
-            .squeeze(D::Minus1)?;
-        let advantage = self.advantage.forward(&xs)?;
-        let value = self.value.forward(&xs)?;
-        &value.repeat(&[1, self.action_count])? + &advantage
-            - &advantage.mean_keepdim(1)?.repeat(&[1, self.action_count])?
+        // The last layer keeps counting from the hidden ones.
+        let head = Head::new(
+            &vs,
+            size,
+            action_count,
+            distribution,
+            StreamShape {
+                hidden: &[],
+                dueling: config.dueling,
+                noisy: config.noisy,
+                prefix: "",
+                first: config.hidden.len() + 1,
+            },
+        )?;
+        Ok(Self {
+            net,
+            head,
+            config: config.clone(),
+        })
+    }
+}
+
+impl Module for MlpQNet {
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        self.head.forward(&self.net.forward(xs)?)
+    }
+}
+
+impl QModel for MlpQNet {
+    fn architecture(&self) -> Architecture {
+        Architecture::Mlp(self.config.clone())
+    }
+
+    fn distribution(&self) -> &ValueDistribution {
+        &self.head.distribution
+    }
+
+    fn forward_dist(&self, xs: &Tensor, taus: Option<&Tensor>) -> candle_core::Result<Tensor> {
+        self.head.forward_dist(&self.net.forward(xs)?, taus)
+    }
+
+    fn resample_noise(&self, rng: &mut dyn RngCore) -> candle_core::Result<()> {
+        self.head.resample_noise(rng)
+    }
+
+    fn remove_noise(&self) {
+        self.head.remove_noise()
+    }
+
+    fn is_noisy(&self) -> bool {
+        self.head.is_noisy()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::DType;
+
+    use super::*;
+
+    #[test]
+    fn tau_embedding_uses_cosines_of_increasing_frequency() {
+        // Identity weights expose the cosines `cos(i * pi * tau)`, after the ReLU.
+        let embedding = TauEmbedding {
+            linear: nn::Linear::new(Tensor::eye(3, DType::F32, &Device::Cpu).unwrap(), None),
+            dim: 3,
+            policy_taus: vec![0.25, 0.75],
+        };
+        let taus = Tensor::new(&[[0_f32, 0.5, 1.]], &Device::Cpu).unwrap();
+        let features = embedding.forward(&taus).unwrap();
+        assert_eq!(features.dims(), [1, 3, 3]);
+        let expected = [[1., 1., 1.], [1., 0., 0.], [1., 0., 1.]];
+        for (row, expected) in features.to_vec3::<f32>().unwrap()[0].iter().zip(expected) {
+            for (value, expected) in row.iter().zip(expected) {
+                assert!((value - expected).abs() < 1e-6, "{row:?}");
+            }
+        }
+
+        let policy_taus = embedding.policy_taus(2, &Device::Cpu).unwrap();
+        assert_eq!(policy_taus.to_vec2::<f32>().unwrap(), [[0.25, 0.75]; 2]);
     }
 }
@@ -0,0 +1,122 @@
+use std::cell::RefCell;
+
+use candle_core::{Module, Result, Tensor};
+use candle_nn::{init::Init, VarBuilder};
+use rand::{Rng, RngCore};
+use rand_distr::StandardNormal;
+
+/// Initial scale of the noise, divided by the square root of the number of inputs.
+const SIGMA_INIT: f64 = 0.5;
+
+/// A linear layer with learned Gaussian noise added to its weights and biases, so the
+/// network explores by itself, from "Noisy Networks for Exploration".
+///
+/// The noise is factorised: each weight's noise is the product of one sample per input
+/// and one per output. It only changes when `resample` is called, and is off until then.
+pub struct NoisyLinear {
+    weight_mu: Tensor,
+    weight_sigma: Tensor,
+    bias_mu: Tensor,
+    bias_sigma: Tensor,
+    /// Noise of the weights and biases, if on.
+    noise: RefCell<Option<(Tensor, Tensor)>>,
+}
+
+/// Creates a `NoisyLinear`, with means initialized like `candle_nn::linear`.
+pub fn noisy_linear(in_dim: usize, out_dim: usize, vs: VarBuilder) -> Result<NoisyLinear> {
+    let bound = 1. / (in_dim as f64).sqrt();
+    let mu = Init::Uniform {
+        lo: -bound,
+        up: bound,
+    };
+    let sigma = Init::Const(SIGMA_INIT * bound);
+    Ok(NoisyLinear {
+        weight_mu: vs.get_with_hints((out_dim, in_dim), "weight_mu", mu)?,
+        weight_sigma: vs.get_with_hints((out_dim, in_dim), "weight_sigma", sigma)?,
+        bias_mu: vs.get_with_hints(out_dim, "bias_mu", mu)?,
+        bias_sigma: vs.get_with_hints(out_dim, "bias_sigma", sigma)?,
+        noise: RefCell::new(None),
+    })
+}
+
+impl NoisyLinear {
+    /// Draws new noise from `rng`.
+    pub fn resample(&self, rng: &mut dyn RngCore) -> Result<()> {
+        let (out_dim, in_dim) = self.weight_mu.dims2()?;
+        let device = self.weight_mu.device();
+        let mut sample = |n: usize| {
+            // Scaled to sign(x) * sqrt(|x|), as in the paper.
+            let values: Vec<_> = (0..n)
+                .map(|_| {
+                    let x: f32 = rng.sample(StandardNormal);
+                    x.signum() * x.abs().sqrt()
+                })
+                .collect();
+            Tensor::from_vec(values, n, device)
+        };
+        let input = sample(in_dim)?;
+        let output = sample(out_dim)?;
+        let weight = output.unsqueeze(1)?.broadcast_mul(&input.unsqueeze(0)?)?;
+        *self.noise.borrow_mut() = Some((weight, output));
+        Ok(())
+    }
+
+    /// Uses the mean weights and biases until noise is resampled.
+    pub fn remove_noise(&self) {
+        *self.noise.borrow_mut() = None;
+    }
+}
+
+impl Module for NoisyLinear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let (weight, bias) = match &*self.noise.borrow() {
+            Some((weight_noise, bias_noise)) => (
+                (&self.weight_mu + (&self.weight_sigma * weight_noise)?)?,
+                (&self.bias_mu + (&self.bias_sigma * bias_noise)?)?,
+            ),
+            None => (self.weight_mu.clone(), self.bias_mu.clone()),
+        };
+        xs.matmul(&weight.t()?)?.broadcast_add(&bias)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::{DType, Device};
+    use candle_nn::VarMap;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn outputs(layer: &NoisyLinear, xs: &Tensor) -> Vec<Vec<f32>> {
+        layer.forward(xs).unwrap().to_vec2().unwrap()
+    }
+
+    #[test]
+    fn noise_changes_only_when_resampled() {
+        let vm = VarMap::new();
+        let layer =
+            noisy_linear(3, 2, VarBuilder::from_varmap(&vm, DType::F32, &Device::Cpu)).unwrap();
+        let xs = Tensor::new(&[[1_f32, -2., 0.5], [0.3, 0.1, -1.]], &Device::Cpu).unwrap();
+        let mean = candle_nn::Linear::new(layer.weight_mu.clone(), Some(layer.bias_mu.clone()))
+            .forward(&xs)
+            .unwrap()
+            .to_vec2::<f32>()
+            .unwrap();
+        assert_eq!(outputs(&layer, &xs), mean);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        layer.resample(&mut rng).unwrap();
+        let noisy = outputs(&layer, &xs);
+        assert_ne!(noisy, mean);
+        assert_eq!(outputs(&layer, &xs), noisy);
+
+        layer.resample(&mut rng).unwrap();
+        let resampled = outputs(&layer, &xs);
+        assert_ne!(resampled, noisy);
+        assert_ne!(resampled, mean);
+
+        layer.remove_noise();
+        assert_eq!(outputs(&layer, &xs), mean);
+    }
+}
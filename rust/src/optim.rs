@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+
+use candle_core::{backprop::GradStore, Result, Tensor, Var};
+use candle_nn::{Optimizer, ParamsAdamW, VarMap};
+
+/// Moments tracked for a single variable.
+struct VarAdamW {
+    name: String,
+    var: Var,
+    first_moment: Var,
+    second_moment: Var,
+}
+
+/// AdamW, matching `candle_nn::AdamW`, but with state that can be saved and restored.
+pub struct AdamW {
+    vars: Vec<VarAdamW>,
+    pub step_t: usize,
+    pub params: ParamsAdamW,
+}
+
+impl AdamW {
+    /// Optimizes all variables in a `VarMap`, keeping their names for checkpoints.
+    pub fn from_varmap(vm: &VarMap, params: ParamsAdamW) -> Result<Self> {
+        let data = vm.data().lock().unwrap();
+        let mut named: Vec<_> = data.iter().map(|(n, v)| (n.clone(), v.clone())).collect();
+        named.sort_by(|a, b| a.0.cmp(&b.0));
+        Self::from_named(named, params)
+    }
+
+    pub fn new_lr(vm: &VarMap, lr: f64) -> Result<Self> {
+        Self::from_varmap(
+            vm,
+            ParamsAdamW {
+                lr,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn from_named(vars: Vec<(String, Var)>, params: ParamsAdamW) -> Result<Self> {
+        let vars = vars
+            .into_iter()
+            .filter(|(_, var)| var.dtype().is_float())
+            .map(|(name, var)| {
+                let first_moment = Var::zeros(var.shape(), var.dtype(), var.device())?;
+                let second_moment = Var::zeros(var.shape(), var.dtype(), var.device())?;
+                Ok(VarAdamW {
+                    name,
+                    var,
+                    first_moment,
+                    second_moment,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            vars,
+            step_t: 0,
+            params,
+        })
+    }
+
+    /// Returns the first and second moments of each variable, keyed by
+    /// `m.<name>` and `v.<name>`.
+    pub fn state(&self) -> HashMap<String, Tensor> {
+        self.vars
+            .iter()
+            .flat_map(|v| {
+                [
+                    (format!("m.{}", v.name), v.first_moment.as_tensor().clone()),
+                    (format!("v.{}", v.name), v.second_moment.as_tensor().clone()),
+                ]
+            })
+            .collect()
+    }
+
+    /// Restores moments returned by `state`.
+    pub fn set_state(&mut self, state: &HashMap<String, Tensor>, step_t: usize) -> Result<()> {
+        for v in &self.vars {
+            let get = |key: String| {
+                state.get(&key).ok_or_else(|| {
+                    candle_core::Error::Msg(format!("Optimizer state is missing `{key}`."))
+                })
+            };
+            v.first_moment.set(get(format!("m.{}", v.name))?)?;
+            v.second_moment.set(get(format!("v.{}", v.name))?)?;
+        }
+        self.step_t = step_t;
+        Ok(())
+    }
+}
+
+impl Optimizer for AdamW {
+    type Config = ParamsAdamW;
+
+    fn new(vars: Vec<Var>, params: ParamsAdamW) -> Result<Self> {
+        let named = vars
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (i.to_string(), v))
+            .collect();
+        Self::from_named(named, params)
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.params.lr
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.params.lr = lr
+    }
+
+    fn step(&mut self, grads: &GradStore) -> Result<()> {
+        self.step_t += 1;
+        let lr = self.params.lr;
+        let lambda = self.params.weight_decay;
+        let lr_lambda = lr * lambda;
+        let beta1 = self.params.beta1;
+        let beta2 = self.params.beta2;
+        let scale_m = 1f64 / (1f64 - beta1.powi(self.step_t as i32));
+        let scale_v = 1f64 / (1f64 - beta2.powi(self.step_t as i32));
+        for var in self.vars.iter() {
+            let theta = &var.var;
+            let m = &var.first_moment;
+            let v = &var.second_moment;
+            if let Some(g) = grads.get(theta) {
+                let next_m = ((m.as_tensor() * beta1)? + (g * (1.0 - beta1))?)?;
+                let next_v = ((v.as_tensor() * beta2)? + (g.sqr()? * (1.0 - beta2))?)?;
+                let m_hat = (&next_m * scale_m)?;
+                let v_hat = (&next_v * scale_v)?;
+                let next_theta = (theta.as_tensor() * (1f64 - lr_lambda))?;
+                let adjusted_grad = (m_hat / (v_hat.sqrt()? + self.params.eps)?)?;
+                let next_theta = (next_theta - (adjusted_grad * lr)?)?;
+                m.set(&next_m)?;
+                v.set(&next_v)?;
+                theta.set(&next_theta)?;
+            }
+        }
+        Ok(())
+    }
+}
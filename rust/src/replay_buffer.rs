@@ -1,10 +1,22 @@
-use anyhow::{Error, Result};
-use candle_core::{DType, Device, IndexOp, Shape, Tensor};
-use rand::{
-    seq::{index::sample_weighted, IteratorRandom, SliceRandom},
-    Rng,
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
 };
 
+use anyhow::{bail, Context, Error, Result};
+use candle_core::{Device, IndexOp, Tensor};
+use rand::Rng;
+use safetensors::{tensor::TensorView, Dtype, SafeTensors};
+
+use crate::{environment::ObsSpace, sum_tree::SumTree};
+
+/// Added to TD errors so no transition has zero priority.
+const PRIORITY_EPSILON: f32 = 0.00001;
+/// Version of the format written by `ReplayBuffer::save`.
+const SAVE_FORMAT_VERSION: &str = "1";
+
+/// Indices, importance sampling weights, states, next states, actions, rewards,
+/// dones, next masks and bootstrap discounts.
 pub type Samples = (
     Vec<usize>,
     Tensor,
@@ -14,60 +26,195 @@ pub type Samples = (
     Tensor,
     Tensor,
     Tensor,
+    Tensor,
 );
 
+/// Preallocated storage for one state per slot.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StateStorage {
+    /// Binary states, packed 8 values to a byte.
+    Bits(Vec<u8>),
+    Floats(Vec<f32>),
+}
+
+impl StateStorage {
+    fn new(obs_space: &ObsSpace, capacity: usize) -> Self {
+        let size = obs_space.size();
+        match obs_space {
+            ObsSpace::MultiBinary(_) => Self::Bits(vec![0; capacity * size.div_ceil(8)]),
+            ObsSpace::Box { .. } => Self::Floats(vec![0.; capacity * size]),
+        }
+    }
+
+    /// Writes a state of `size` elements into a slot.
+    fn write(&mut self, slot: usize, size: usize, state: &[f32]) {
+        match self {
+            Self::Bits(bits) => {
+                let stride = size.div_ceil(8);
+                let bytes = &mut bits[slot * stride..(slot + 1) * stride];
+                bytes.fill(0);
+                for (i, &v) in state.iter().enumerate() {
+                    if v > 0.5 {
+                        bytes[i / 8] |= 1 << (i % 8);
+                    }
+                }
+            }
+            Self::Floats(floats) => floats[slot * size..(slot + 1) * size].copy_from_slice(state),
+        }
+    }
+
+    /// Appends the states in `slots` to `out`.
+    fn gather(&self, slots: &[usize], size: usize, out: &mut Vec<f32>) {
+        match self {
+            Self::Bits(bits) => {
+                let stride = size.div_ceil(8);
+                for &slot in slots {
+                    let bytes = &bits[slot * stride..(slot + 1) * stride];
+                    for (byte_i, &byte) in bytes.iter().enumerate() {
+                        let count = (size - byte_i * 8).min(8);
+                        out.extend((0..count).map(|bit| ((byte >> bit) & 1) as f32));
+                    }
+                }
+            }
+            Self::Floats(floats) => {
+                for &slot in slots {
+                    out.extend_from_slice(&floats[slot * size..(slot + 1) * size]);
+                }
+            }
+        }
+    }
+}
+
+fn f32_bytes(values: &[f32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+fn u32_bytes(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+fn bytes_f32(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+        .collect()
+}
+
+fn bytes_u32(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .collect()
+}
+
+/// A single environment step waiting to be folded into an n-step transition.
+struct PendingStep {
+    state: Vec<f32>,
+    next_state: Vec<f32>,
+    action: u32,
+    reward: f32,
+    done: bool,
+    mask: Vec<f32>,
+}
+
 /// A replay buffer for use with off policy algorithms.
 /// Stores transitions and generates mini batches.
+///
+/// Each field is kept in one flat, preallocated array indexed by slot, so batches are
+/// gathered with a single copy per field.
+///
+/// Transitions span up to `n_step` environment steps. The stored reward is the
+/// discounted sum over those steps, and the stored discount is what the bootstrapped
+/// value of the next state should be multiplied by.
 pub struct ReplayBuffer {
     pub capacity: usize,
     pub next: usize,
-    pub states: Vec<Tensor>,
-    pub next_states: Vec<Tensor>,
-    pub actions: Vec<Tensor>,
+    /// Shape of a single state.
+    pub state_shape: Vec<usize>,
+    pub action_count: usize,
+    pub states: StateStorage,
+    pub next_states: StateStorage,
+    pub actions: Vec<u32>,
     pub rewards: Vec<f32>,
+    /// Technically this is the "terminated" flag.
     pub dones: Vec<bool>,
-    pub masks: Vec<Tensor>,
-    pub priorities: Vec<f32>,
+    /// `action_count` entries per slot, 1 if the action is masked.
+    pub masks: Vec<f32>,
+    pub discounts: Vec<f32>,
+    /// Sampling weights, priorities raised to `alpha`.
+    pub priorities: SumTree,
     pub filled: bool,
     pub max_priority: f32,
+    /// If false, transitions are sampled uniformly.
+    pub prioritized: bool,
+    /// How strongly priorities affect sampling. 0 is uniform.
+    pub alpha: f32,
+    /// Number of environment steps each transition covers.
+    pub n_step: usize,
+    /// Discount applied to rewards when accumulating n-step returns.
+    pub discount: f32,
+    /// Recent steps of each environment that haven't been stored yet.
+    pending: Vec<VecDeque<PendingStep>>,
 }
 
 impl ReplayBuffer {
-    pub fn new(state_shape: Shape, capacity: usize) -> Self {
-        let s = move || -> Result<_, candle_core::Error> {
-            // let k = DType::F32;
-            // let state_shape = [&[capacity], state_shape.dims()].concat();
-            // let d = candle_core::Device::Cpu;
-            let states = Vec::new();
-            let next_states = Vec::new();
-            let actions = Vec::new();
-            let rewards = Vec::new();
-            let masks = Vec::new();
-            let priorities = Vec::new();
-            // Technically this is the "terminated" flag
-            let dones = Vec::new();
-            let filled = false;
-            let next = 0;
-            Ok(Self {
-                capacity,
-                next,
-                states,
-                next_states,
-                actions,
-                rewards,
-                dones,
-                filled,
-                max_priority: 0.1,
-                priorities,
-                masks,
-            })
-        }()
-        .unwrap();
-        s
+    /// Creates a buffer that samples transitions uniformly.
+    pub fn new(obs_space: &ObsSpace, action_count: usize, capacity: usize) -> Self {
+        Self::new_prioritized(obs_space, action_count, capacity, false, 0.)
+    }
+
+    /// Creates a buffer that samples transitions proportional to their TD error
+    /// raised to `alpha`, if `prioritized` is set.
+    pub fn new_prioritized(
+        obs_space: &ObsSpace,
+        action_count: usize,
+        capacity: usize,
+        prioritized: bool,
+        alpha: f32,
+    ) -> Self {
+        Self {
+            capacity,
+            next: 0,
+            state_shape: obs_space.shape(),
+            action_count,
+            states: StateStorage::new(obs_space, capacity),
+            next_states: StateStorage::new(obs_space, capacity),
+            actions: vec![0; capacity],
+            rewards: vec![0.; capacity],
+            dones: vec![false; capacity],
+            masks: vec![0.; capacity * action_count],
+            discounts: vec![0.; capacity],
+            priorities: SumTree::new(capacity),
+            filled: false,
+            max_priority: 0.1,
+            prioritized,
+            alpha,
+            n_step: 1,
+            discount: 1.,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Makes each stored transition cover `n_step` environment steps, with rewards
+    /// discounted by `discount`.
+    pub fn with_n_step(mut self, n_step: usize, discount: f32) -> Self {
+        assert!(n_step > 0, "n_step must be at least 1.");
+        self.n_step = n_step;
+        self.discount = discount;
+        self
+    }
+
+    /// Number of elements in a single state.
+    fn state_size(&self) -> usize {
+        self.state_shape.iter().product()
     }
 
     /// Inserts a transition from each environment into the buffer. Make sure
     /// more data than steps aren't inserted.
+    ///
+    /// `dones` marks terminated episodes and `truncs` marks episodes cut short. Both end
+    /// the current n-step window, but only `dones` stops bootstrapping.
+    #[allow(clippy::too_many_arguments)]
     pub fn insert_step(
         &mut self,
         states: Tensor,
@@ -75,98 +222,639 @@ impl ReplayBuffer {
         actions: Tensor,
         rewards: &[f32],
         dones: &[bool],
+        truncs: &[bool],
         masks: Tensor,
-    ) {
-        move || -> Result<_> {
-            let batch_size = dones.len();
-            let indices = (self.next..(self.next + batch_size))
-                .map(|i| i % self.capacity)
-                .collect::<Vec<_>>();
-            for (val_i, &i) in indices.iter().enumerate() {
-                if self.filled {
-                    self.states[i] = states.i(val_i)?;
-                    self.next_states[i] = next_states.i(val_i)?;
-                    self.actions[i] = actions.i(val_i)?;
-                    self.rewards[i] = rewards[val_i];
-                    self.dones[i] = dones[val_i];
-                    self.masks[i] = masks.i(val_i)?;
-                    self.priorities[i] = self.max_priority;
-                } else {
-                    self.states.push(states.i(val_i)?);
-                    self.next_states.push(next_states.i(val_i)?);
-                    self.actions.push(actions.i(val_i)?);
-                    self.rewards.push(rewards[val_i]);
-                    self.dones.push(dones[val_i]);
-                    self.masks.push(masks.i(val_i)?);
-                    self.priorities.push(self.max_priority);
-                }
+    ) -> candle_core::Result<()> {
+        let batch_size = dones.len();
+        if self.pending.len() < batch_size {
+            self.pending.resize_with(batch_size, VecDeque::new);
+        }
+        for val_i in 0..batch_size {
+            self.pending[val_i].push_back(PendingStep {
+                state: states.i(val_i)?.flatten_all()?.to_vec1()?,
+                next_state: next_states.i(val_i)?.flatten_all()?.to_vec1()?,
+                action: actions.i(val_i)?.to_scalar()?,
+                reward: rewards[val_i],
+                done: dones[val_i],
+                mask: masks.i(val_i)?.to_vec1()?,
+            });
+            if self.pending[val_i].len() == self.n_step {
+                self.store_pending(val_i);
             }
-            self.next = (self.next + batch_size) % self.capacity;
-            if self.next == 0 {
-                self.filled = true;
+            // Flush the rest of the window when the episode ends.
+            if dones[val_i] || truncs[val_i] {
+                while !self.pending[val_i].is_empty() {
+                    self.store_pending(val_i);
+                }
             }
-            Ok(())
-        }()
-        .unwrap()
+        }
+        Ok(())
+    }
+
+    /// Folds the pending steps of an environment into one transition, stores it, and
+    /// drops the oldest step.
+    fn store_pending(&mut self, env_i: usize) {
+        let size = self.state_size();
+        let window = &self.pending[env_i];
+        let first = window.front().unwrap();
+        let last = window.back().unwrap();
+        let mut reward = 0.;
+        let mut discount = 1.;
+        for step in window {
+            reward += discount * step.reward;
+            discount *= self.discount;
+        }
+
+        let i = self.next;
+        self.states.write(i, size, &first.state);
+        self.next_states.write(i, size, &last.next_state);
+        self.actions[i] = first.action;
+        self.rewards[i] = reward;
+        self.dones[i] = last.done;
+        self.masks[i * self.action_count..(i + 1) * self.action_count].copy_from_slice(&last.mask);
+        self.discounts[i] = discount;
+        self.priorities.set(i, self.max_priority.powf(self.alpha));
+        self.next = (self.next + 1) % self.capacity;
+        if self.next == 0 {
+            self.filled = true;
+        }
+        self.pending[env_i].pop_front();
+    }
+
+    /// Returns the number of transitions stored.
+    pub fn len(&self) -> usize {
+        if self.filled {
+            self.capacity
+        } else {
+            self.next
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
     /// Generates minibatches of experience.
-    pub fn sample(&self, batch_size: usize) -> Result<Samples, Error> {
-        let mut rng = rand::thread_rng();
-        let sum_priorities: f32 = self.priorities.iter().sum();
-        let probs: Vec<_> = self
-            .priorities
+    ///
+    /// When prioritized, also returns importance sampling weights with exponent `beta`,
+    /// normalized so the largest weight in the batch is 1. Otherwise, all weights are 1.
+    pub fn sample(
+        &self,
+        batch_size: usize,
+        beta: f32,
+        rng: &mut impl Rng,
+    ) -> Result<Samples, Error> {
+        let len = self.len();
+        let (indices, weights): (Vec<_>, Vec<_>) = if self.prioritized {
+            // Split the total into equal segments and draw one sample from each.
+            let total = self.priorities.total();
+            let segment = total / batch_size as f32;
+            let indices: Vec<_> = (0..batch_size)
+                .map(|i| {
+                    let value = segment * (i as f32 + rng.gen::<f32>());
+                    self.priorities.find(value).min(len - 1)
+                })
+                .collect();
+            let weights: Vec<_> = indices
+                .iter()
+                .map(|&i| (len as f32 * self.priorities.get(i) / total).powf(-beta))
+                .collect();
+            let max_weight = weights.iter().copied().fold(f32::MIN, f32::max);
+            indices
+                .into_iter()
+                .zip(weights.into_iter().map(|w| w / max_weight))
+                .unzip()
+        } else {
+            (0..batch_size).map(|_| (rng.gen_range(0..len), 1.)).unzip()
+        };
+
+        let size = self.state_size();
+        let mut rand_states = Vec::with_capacity(batch_size * size);
+        let mut rand_next_states = Vec::with_capacity(batch_size * size);
+        self.states.gather(&indices, size, &mut rand_states);
+        self.next_states
+            .gather(&indices, size, &mut rand_next_states);
+        let mut rand_masks = Vec::with_capacity(batch_size * self.action_count);
+        for &i in &indices {
+            rand_masks
+                .extend_from_slice(&self.masks[i * self.action_count..(i + 1) * self.action_count]);
+        }
+        let rand_actions: Vec<_> = indices.iter().map(|&i| self.actions[i]).collect();
+        let rand_rewards: Vec<_> = indices.iter().map(|&i| self.rewards[i]).collect();
+        let rand_dones: Vec<_> = indices
             .iter()
-            .map(|p| 1.
-                // *p / sum_priorities
-            )
+            .map(|&i| if self.dones[i] { 1_f32 } else { 0. })
             .collect();
-        let indices: Vec<_> = sample_weighted(&mut rng, self.capacity, |i| probs[i], batch_size)
-            .unwrap()
-            .into_vec();
-        let mut rand_states_vec = Vec::new();
-        let mut rand_next_states_vec = Vec::new();
-        let mut rand_actions_vec = Vec::new();
-        let mut rand_rewards_vec = Vec::new();
-        let mut rand_dones_vec = Vec::new();
-        let mut rand_masks_vec = Vec::new();
-        for &i in &indices {
-            rand_states_vec.push(&self.states[i]);
-            rand_next_states_vec.push(&self.next_states[i]);
-            rand_actions_vec.push(&self.actions[i]);
-            rand_rewards_vec.push(self.rewards[i]);
-            rand_dones_vec.push(if self.dones[i] { 1_f32 } else { 0. });
-            rand_masks_vec.push(&self.masks[i]);
-        }
-        let probs = Tensor::new(probs, &Device::Cpu)?.gather(
-            &Tensor::new(
-                indices
-                    .iter()
-                    .copied()
-                    .map(|i| i as u32)
-                    .collect::<Vec<_>>(),
-                &Device::Cpu,
-            )?,
-            0,
-        )?;
+        let rand_discounts: Vec<_> = indices.iter().map(|&i| self.discounts[i]).collect();
+
+        let batch_shape = [&[batch_size], self.state_shape.as_slice()].concat();
         Ok((
             indices,
-            probs,
-            Tensor::stack(&rand_states_vec, 0)?,
-            Tensor::stack(&rand_next_states_vec, 0)?,
-            Tensor::stack(&rand_actions_vec, 0)?,
-            Tensor::new(rand_rewards_vec, &Device::Cpu)?,
-            Tensor::new(rand_dones_vec, &Device::Cpu)?,
-            Tensor::stack(&rand_masks_vec, 0)?,
+            Tensor::new(weights, &Device::Cpu)?,
+            Tensor::from_vec(rand_states, batch_shape.as_slice(), &Device::Cpu)?,
+            Tensor::from_vec(rand_next_states, batch_shape.as_slice(), &Device::Cpu)?,
+            Tensor::new(rand_actions, &Device::Cpu)?,
+            Tensor::new(rand_rewards, &Device::Cpu)?,
+            Tensor::new(rand_dones, &Device::Cpu)?,
+            Tensor::from_vec(rand_masks, (batch_size, self.action_count), &Device::Cpu)?,
+            Tensor::new(rand_discounts, &Device::Cpu)?,
         ))
     }
 
+    /// Saves the buffer, including transitions that haven't been folded into n-step
+    /// transitions yet, as a safetensors file.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let (storage, states, next_states, state_dtype) = match (&self.states, &self.next_states) {
+            (StateStorage::Bits(states), StateStorage::Bits(next_states)) => {
+                ("bits", states.clone(), next_states.clone(), Dtype::U8)
+            }
+            (StateStorage::Floats(states), StateStorage::Floats(next_states)) => (
+                "floats",
+                f32_bytes(states),
+                f32_bytes(next_states),
+                Dtype::F32,
+            ),
+            _ => unreachable!(),
+        };
+        let state_len = states.len() / self.capacity / state_dtype.size();
+
+        // Pending steps of all environments, flattened.
+        let pending: Vec<_> = self
+            .pending
+            .iter()
+            .enumerate()
+            .flat_map(|(env_i, steps)| steps.iter().map(move |step| (env_i as u32, step)))
+            .collect();
+        let pending_count = pending.len();
+        let size = self.state_size();
+        let pending_env: Vec<_> = pending.iter().map(|(env_i, _)| *env_i).collect();
+        let pending_states: Vec<_> = pending.iter().flat_map(|(_, s)| s.state.clone()).collect();
+        let pending_next_states: Vec<_> = pending
+            .iter()
+            .flat_map(|(_, s)| s.next_state.clone())
+            .collect();
+        let pending_actions: Vec<_> = pending.iter().map(|(_, s)| s.action).collect();
+        let pending_rewards: Vec<_> = pending.iter().map(|(_, s)| s.reward).collect();
+        let pending_dones: Vec<_> = pending.iter().map(|(_, s)| s.done as u8).collect();
+        let pending_masks: Vec<_> = pending.iter().flat_map(|(_, s)| s.mask.clone()).collect();
+
+        let dones: Vec<_> = self.dones.iter().map(|&d| d as u8).collect();
+        let data = [
+            (
+                "states",
+                state_dtype,
+                vec![self.capacity, state_len],
+                states,
+            ),
+            (
+                "next_states",
+                state_dtype,
+                vec![self.capacity, state_len],
+                next_states,
+            ),
+            (
+                "actions",
+                Dtype::U32,
+                vec![self.capacity],
+                u32_bytes(&self.actions),
+            ),
+            (
+                "rewards",
+                Dtype::F32,
+                vec![self.capacity],
+                f32_bytes(&self.rewards),
+            ),
+            ("dones", Dtype::U8, vec![self.capacity], dones),
+            (
+                "masks",
+                Dtype::F32,
+                vec![self.capacity, self.action_count],
+                f32_bytes(&self.masks),
+            ),
+            (
+                "discounts",
+                Dtype::F32,
+                vec![self.capacity],
+                f32_bytes(&self.discounts),
+            ),
+            (
+                "priorities",
+                Dtype::F32,
+                vec![self.capacity],
+                f32_bytes(self.priorities.values()),
+            ),
+            (
+                "pending_env",
+                Dtype::U32,
+                vec![pending_count],
+                u32_bytes(&pending_env),
+            ),
+            (
+                "pending_states",
+                Dtype::F32,
+                vec![pending_count, size],
+                f32_bytes(&pending_states),
+            ),
+            (
+                "pending_next_states",
+                Dtype::F32,
+                vec![pending_count, size],
+                f32_bytes(&pending_next_states),
+            ),
+            (
+                "pending_actions",
+                Dtype::U32,
+                vec![pending_count],
+                u32_bytes(&pending_actions),
+            ),
+            (
+                "pending_rewards",
+                Dtype::F32,
+                vec![pending_count],
+                f32_bytes(&pending_rewards),
+            ),
+            (
+                "pending_dones",
+                Dtype::U8,
+                vec![pending_count],
+                pending_dones,
+            ),
+            (
+                "pending_masks",
+                Dtype::F32,
+                vec![pending_count, self.action_count],
+                f32_bytes(&pending_masks),
+            ),
+        ];
+        let views = data
+            .iter()
+            .map(|(name, dtype, shape, bytes)| {
+                Ok((*name, TensorView::new(*dtype, shape.clone(), bytes)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let state_shape: Vec<_> = self.state_shape.iter().map(|d| d.to_string()).collect();
+        let metadata: HashMap<_, _> = [
+            ("format_version", SAVE_FORMAT_VERSION.to_string()),
+            ("storage", storage.to_string()),
+            ("state_shape", state_shape.join(",")),
+            ("action_count", self.action_count.to_string()),
+            ("capacity", self.capacity.to_string()),
+            ("next", self.next.to_string()),
+            ("filled", self.filled.to_string()),
+            ("max_priority", self.max_priority.to_string()),
+            ("prioritized", self.prioritized.to_string()),
+            ("alpha", self.alpha.to_string()),
+            ("n_step", self.n_step.to_string()),
+            ("discount", self.discount.to_string()),
+            ("env_count", self.pending.len().to_string()),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+
+        let path = path.as_ref();
+        safetensors::serialize_to_file(views, &Some(metadata), path)
+            .with_context(|| format!("Could not save replay buffer to {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Loads a buffer written by `ReplayBuffer::save`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)
+            .with_context(|| format!("Could not read replay buffer {}", path.display()))?;
+        let (_, header) = SafeTensors::read_metadata(&data)
+            .with_context(|| format!("Invalid replay buffer file {}", path.display()))?;
+        let tensors = SafeTensors::deserialize(&data)?;
+        let metadata = header
+            .metadata()
+            .as_ref()
+            .context("Replay buffer file has no metadata.")?;
+        let get = |key: &str| {
+            metadata
+                .get(key)
+                .with_context(|| format!("Replay buffer metadata is missing `{key}`."))
+        };
+        fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T> {
+            value
+                .parse()
+                .ok()
+                .with_context(|| format!("Invalid value `{value}` for `{key}`."))
+        }
+        let version = get("format_version")?;
+        if version != SAVE_FORMAT_VERSION {
+            bail!("Unsupported replay buffer format version {version}.");
+        }
+
+        let state_shape = get("state_shape")?
+            .split(',')
+            .map(|d| parse("state_shape", d))
+            .collect::<Result<Vec<usize>>>()?;
+        let action_count: usize = parse("action_count", get("action_count")?)?;
+        let capacity: usize = parse("capacity", get("capacity")?)?;
+        let size: usize = state_shape.iter().product();
+        let tensor = |name: &str, dtype: Dtype, shape: &[usize]| -> Result<Vec<u8>> {
+            let view = tensors.tensor(name)?;
+            if view.dtype() != dtype || view.shape() != shape {
+                bail!(
+                    "Expected `{name}` to be {dtype:?} with shape {shape:?}, found {:?} with shape {:?}.",
+                    view.dtype(),
+                    view.shape()
+                );
+            }
+            Ok(view.data().to_vec())
+        };
+
+        let (states, next_states) = match get("storage")?.as_str() {
+            "bits" => {
+                let shape = [capacity, size.div_ceil(8)];
+                (
+                    StateStorage::Bits(tensor("states", Dtype::U8, &shape)?),
+                    StateStorage::Bits(tensor("next_states", Dtype::U8, &shape)?),
+                )
+            }
+            "floats" => {
+                let shape = [capacity, size];
+                (
+                    StateStorage::Floats(bytes_f32(&tensor("states", Dtype::F32, &shape)?)),
+                    StateStorage::Floats(bytes_f32(&tensor("next_states", Dtype::F32, &shape)?)),
+                )
+            }
+            storage => bail!("Unknown state storage `{storage}`."),
+        };
+
+        let mut priorities = SumTree::new(capacity);
+        for (i, p) in bytes_f32(&tensor("priorities", Dtype::F32, &[capacity])?)
+            .into_iter()
+            .enumerate()
+        {
+            priorities.set(i, p);
+        }
+
+        let env_count: usize = parse("env_count", get("env_count")?)?;
+        let pending_count = tensors
+            .tensor("pending_env")?
+            .shape()
+            .first()
+            .copied()
+            .unwrap_or(0);
+        let pending_env = bytes_u32(&tensor("pending_env", Dtype::U32, &[pending_count])?);
+        let pending_states = bytes_f32(&tensor(
+            "pending_states",
+            Dtype::F32,
+            &[pending_count, size],
+        )?);
+        let pending_next_states = bytes_f32(&tensor(
+            "pending_next_states",
+            Dtype::F32,
+            &[pending_count, size],
+        )?);
+        let pending_actions = bytes_u32(&tensor("pending_actions", Dtype::U32, &[pending_count])?);
+        let pending_rewards = bytes_f32(&tensor("pending_rewards", Dtype::F32, &[pending_count])?);
+        let pending_dones = tensor("pending_dones", Dtype::U8, &[pending_count])?;
+        let pending_masks = bytes_f32(&tensor(
+            "pending_masks",
+            Dtype::F32,
+            &[pending_count, action_count],
+        )?);
+        let mut pending: Vec<_> = (0..env_count).map(|_| VecDeque::new()).collect();
+        for i in 0..pending_count {
+            let env_i = pending_env[i] as usize;
+            if env_i >= env_count {
+                bail!("Pending step refers to environment {env_i}, but there are {env_count}.");
+            }
+            pending[env_i].push_back(PendingStep {
+                state: pending_states[i * size..(i + 1) * size].to_vec(),
+                next_state: pending_next_states[i * size..(i + 1) * size].to_vec(),
+                action: pending_actions[i],
+                reward: pending_rewards[i],
+                done: pending_dones[i] != 0,
+                mask: pending_masks[i * action_count..(i + 1) * action_count].to_vec(),
+            });
+        }
+
+        let next: usize = parse("next", get("next")?)?;
+        if next >= capacity {
+            bail!("Replay buffer position {next} is out of bounds for capacity {capacity}.");
+        }
+        Ok(Self {
+            capacity,
+            next,
+            state_shape,
+            action_count,
+            states,
+            next_states,
+            actions: bytes_u32(&tensor("actions", Dtype::U32, &[capacity])?),
+            rewards: bytes_f32(&tensor("rewards", Dtype::F32, &[capacity])?),
+            dones: tensor("dones", Dtype::U8, &[capacity])?
+                .into_iter()
+                .map(|d| d != 0)
+                .collect(),
+            masks: bytes_f32(&tensor("masks", Dtype::F32, &[capacity, action_count])?),
+            discounts: bytes_f32(&tensor("discounts", Dtype::F32, &[capacity])?),
+            priorities,
+            filled: parse("filled", get("filled")?)?,
+            max_priority: parse("max_priority", get("max_priority")?)?,
+            prioritized: parse("prioritized", get("prioritized")?)?,
+            alpha: parse("alpha", get("alpha")?)?,
+            n_step: parse("n_step", get("n_step")?)?,
+            discount: parse("discount", get("discount")?)?,
+            pending,
+        })
+    }
+
     /// Updates transition TD errors.
     pub fn update_errors(&mut self, indices: &[usize], errors: &[f32]) {
         for (&i, &error) in indices.iter().zip(errors) {
-            let priority = error.abs() + 0.00001;
-            self.priorities[i] = priority;
+            let priority = error.abs() + PRIORITY_EPSILON;
+            self.priorities.set(i, priority.powf(self.alpha));
             self.max_priority = self.max_priority.max(priority);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    const ENVS: usize = 2;
+    const ACTIONS: usize = 3;
+
+    /// Inserts a step for each environment, ending episodes on some steps so windows
+    /// of different lengths are left pending.
+    fn insert(buffer: &mut ReplayBuffer, step: usize) {
+        let obs = |offset: usize| {
+            let values: Vec<_> = (0..ENVS * 4)
+                .map(|i| ((step + offset) * 10 + i) as f32)
+                .collect();
+            Tensor::from_vec(values, (ENVS, 4), &Device::Cpu).unwrap()
+        };
+        let actions: Vec<_> = (0..ENVS).map(|i| ((step + i) % ACTIONS) as u32).collect();
+        let rewards: Vec<_> = (0..ENVS).map(|i| (step * ENVS + i) as f32 * 0.1).collect();
+        let dones: Vec<_> = (0..ENVS).map(|i| (step + i) % 7 == 6).collect();
+        let truncs: Vec<_> = (0..ENVS).map(|i| (step + i) % 5 == 4).collect();
+        let masks: Vec<_> = (0..ENVS * ACTIONS)
+            .map(|i| ((step + i) % 4 == 1) as u8 as f32)
+            .collect();
+        buffer
+            .insert_step(
+                obs(0),
+                obs(1),
+                Tensor::new(actions, &Device::Cpu).unwrap(),
+                &rewards,
+                &dones,
+                &truncs,
+                Tensor::from_vec(masks, (ENVS, ACTIONS), &Device::Cpu).unwrap(),
+            )
+            .unwrap();
+    }
+
+    /// Samples a batch and flattens every part of it to compare batches exactly.
+    fn sample(buffer: &ReplayBuffer, seed: u64) -> (Vec<usize>, Vec<Vec<f32>>) {
+        let (indices, weights, states, next_states, actions, rewards, dones, masks, discounts) =
+            buffer
+                .sample(8, 0.5, &mut StdRng::seed_from_u64(seed))
+                .unwrap();
+        let tensors = [
+            weights,
+            states,
+            next_states,
+            actions,
+            rewards,
+            dones,
+            masks,
+            discounts,
+        ];
+        let values = tensors
+            .iter()
+            .map(|t| {
+                t.to_dtype(candle_core::DType::F32)
+                    .unwrap()
+                    .flatten_all()
+                    .unwrap()
+                    .to_vec1()
+                    .unwrap()
+            })
+            .collect();
+        (indices, values)
+    }
+
+    /// Inserts a four step episode with rewards 1 to 4 into a 3-step buffer, ending it
+    /// with `done` or `trunc` on the last step. State `k` is step `k`'s observation.
+    fn n_step_episode(done: bool, trunc: bool) -> ReplayBuffer {
+        let obs_space = ObsSpace::Box {
+            low: vec![0.],
+            high: vec![10.],
+        };
+        let mut buffer = ReplayBuffer::new(&obs_space, ACTIONS, 8).with_n_step(3, 0.5);
+        for step in 0..4 {
+            let last = step == 3;
+            let obs = |k: usize| Tensor::new(&[[k as f32]], &Device::Cpu).unwrap();
+            buffer
+                .insert_step(
+                    obs(step),
+                    obs(step + 1),
+                    Tensor::new(&[0_u32], &Device::Cpu).unwrap(),
+                    &[step as f32 + 1.],
+                    &[last && done],
+                    &[last && trunc],
+                    Tensor::zeros((1, ACTIONS), candle_core::DType::F32, &Device::Cpu).unwrap(),
+                )
+                .unwrap();
+        }
+        buffer
+    }
+
+    #[test]
+    fn n_step_windows_fold_rewards_and_discounts() {
+        for (done, trunc) in [(true, false), (false, true)] {
+            let buffer = n_step_episode(done, trunc);
+            assert_eq!(buffer.len(), 4);
+            assert!(buffer.pending[0].is_empty());
+            // Full windows from steps 0 and 1, then the shorter windows left at the end.
+            assert_eq!(buffer.rewards[..4], [2.75, 4.5, 5., 4.]);
+            assert_eq!(buffer.discounts[..4], [0.125, 0.125, 0.25, 0.5]);
+            assert_eq!(buffer.dones[..4], [false, done, done, done]);
+            let StateStorage::Floats(next_states) = &buffer.next_states else {
+                panic!("box observations are stored as floats");
+            };
+            assert_eq!(next_states[..4], [3., 4., 4., 4.]);
+            let StateStorage::Floats(states) = &buffer.states else {
+                panic!("box observations are stored as floats");
+            };
+            assert_eq!(states[..4], [0., 1., 2., 3.]);
+        }
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let obs_space = ObsSpace::Box {
+            low: vec![0.; 4],
+            high: vec![100.; 4],
+        };
+        let mut buffer =
+            ReplayBuffer::new_prioritized(&obs_space, ACTIONS, 16, true, 0.6).with_n_step(3, 0.9);
+        for step in 0..12 {
+            insert(&mut buffer, step);
+        }
+        let (indices, _) = sample(&buffer, 0);
+        let errors: Vec<_> = (0..indices.len()).map(|i| i as f32 * 0.7).collect();
+        buffer.update_errors(&indices, &errors);
+        assert!(buffer.pending.iter().any(|steps| !steps.is_empty()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("buffer.safetensors");
+        buffer.save(&path).unwrap();
+        let mut loaded = ReplayBuffer::load(&path).unwrap();
+
+        assert_eq!(loaded.next, buffer.next);
+        assert_eq!(loaded.filled, buffer.filled);
+        assert_eq!(loaded.max_priority, buffer.max_priority);
+        assert_eq!(loaded.priorities.values(), buffer.priorities.values());
+        for seed in 0..4 {
+            assert_eq!(sample(&loaded, seed), sample(&buffer, seed));
+        }
+
+        // The pending steps are folded in the same way once more steps arrive.
+        for step in 12..20 {
+            insert(&mut buffer, step);
+            insert(&mut loaded, step);
+        }
+        for seed in 0..4 {
+            assert_eq!(sample(&loaded, seed), sample(&buffer, seed));
+        }
+    }
+
+    #[test]
+    fn save_and_load_packed_states() {
+        let obs_space = ObsSpace::MultiBinary(vec![2, 5]);
+        let mut buffer = ReplayBuffer::new(&obs_space, ACTIONS, 8);
+        for step in 0..6 {
+            let states: Vec<_> = (0..10)
+                .map(|i| ((step + i) % 3 == 1) as u8 as f32)
+                .collect();
+            let states = Tensor::from_vec(states, (1, 2, 5), &Device::Cpu).unwrap();
+            buffer
+                .insert_step(
+                    states.clone(),
+                    states,
+                    Tensor::new(&[1_u32], &Device::Cpu).unwrap(),
+                    &[1.],
+                    &[false],
+                    &[false],
+                    Tensor::zeros((1, ACTIONS), candle_core::DType::F32, &Device::Cpu).unwrap(),
+                )
+                .unwrap();
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("buffer.safetensors");
+        buffer.save(&path).unwrap();
+        let loaded = ReplayBuffer::load(&path).unwrap();
+        assert_eq!(loaded.states, buffer.states);
+        assert_eq!(sample(&loaded, 0), sample(&buffer, 0));
+    }
+}
@@ -0,0 +1,367 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{bail, Context, Result};
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use safetensors::{tensor::TensorView, Dtype, SafeTensors};
+
+use crate::{
+    env::{DEFAULT_GRID_SIZE, NUM_CHANNELS},
+    environment::{ActSpace, Environment, ObsSpace},
+    model::{Architecture, QModel, QNetConfig, ValueDistribution},
+};
+
+/// Version of the metadata written by `save_model`.
+const MODEL_FORMAT_VERSION: &str = "1";
+
+/// Describes a saved model, stored in the metadata of its safetensors file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelMeta {
+    pub architecture: Architecture,
+    /// What the network predicts about returns, which decides its outputs.
+    pub distribution: ValueDistribution,
+    /// Shape of a single observation the model was trained on.
+    pub obs_shape: Vec<usize>,
+    pub action_count: usize,
+    /// `Environment::NAME` of the environment the model was trained on.
+    pub env_name: String,
+    pub env_version: u32,
+    /// Number of training iterations run before saving.
+    pub step: usize,
+    /// `TrainConfig::hash` of the run's config.
+    pub config_hash: String,
+}
+
+impl ModelMeta {
+    /// Describes a model trained on environments like `env`.
+    pub fn new<E: Environment>(
+        env: &E,
+        architecture: Architecture,
+        distribution: ValueDistribution,
+        step: usize,
+        config_hash: String,
+    ) -> Self {
+        Self {
+            architecture,
+            distribution,
+            obs_shape: env.observation_space().shape(),
+            action_count: env.action_space().size(),
+            env_name: E::NAME.to_string(),
+            env_version: E::VERSION,
+            step,
+            config_hash,
+        }
+    }
+
+    /// Models saved before metadata was added all hold the grid `QNet`.
+    fn legacy() -> Self {
+        Self {
+            architecture: Architecture::QNet(QNetConfig::default()),
+            distribution: ValueDistribution::Expected,
+            obs_shape: vec![NUM_CHANNELS, DEFAULT_GRID_SIZE, DEFAULT_GRID_SIZE],
+            action_count: 4,
+            env_name: "grid".into(),
+            env_version: 1,
+            step: 0,
+            config_hash: String::new(),
+        }
+    }
+
+    fn to_metadata(&self) -> Result<HashMap<String, String>> {
+        Ok([
+            ("format_version", MODEL_FORMAT_VERSION.to_string()),
+            ("architecture", serde_json::to_string(&self.architecture)?),
+            ("distribution", serde_json::to_string(&self.distribution)?),
+            ("obs_shape", serde_json::to_string(&self.obs_shape)?),
+            ("action_count", self.action_count.to_string()),
+            ("env_name", self.env_name.clone()),
+            ("env_version", self.env_version.to_string()),
+            ("step", self.step.to_string()),
+            ("config_hash", self.config_hash.clone()),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect())
+    }
+
+    fn from_metadata(metadata: &HashMap<String, String>) -> Result<Self> {
+        let get = |key: &str| {
+            metadata
+                .get(key)
+                .with_context(|| format!("Model metadata is missing `{key}`."))
+        };
+        fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T> {
+            value
+                .parse()
+                .ok()
+                .with_context(|| format!("Invalid value `{value}` for `{key}`."))
+        }
+        let version = get("format_version")?;
+        if version != MODEL_FORMAT_VERSION {
+            bail!("Unsupported model format version {version}, expected {MODEL_FORMAT_VERSION}.");
+        }
+        Ok(Self {
+            architecture: serde_json::from_str(get("architecture")?)
+                .context("Invalid value for `architecture`.")?,
+            // Missing from models saved before distributional networks were added.
+            distribution: match metadata.get("distribution") {
+                Some(value) => {
+                    serde_json::from_str(value).context("Invalid value for `distribution`.")?
+                }
+                None => ValueDistribution::Expected,
+            },
+            obs_shape: serde_json::from_str(get("obs_shape")?)
+                .context("Invalid value for `obs_shape`.")?,
+            action_count: parse("action_count", get("action_count")?)?,
+            env_name: get("env_name")?.clone(),
+            env_version: parse("env_version", get("env_version")?)?,
+            step: parse("step", get("step")?)?,
+            config_hash: get("config_hash")?.clone(),
+        })
+    }
+
+    /// Checks that the model was trained on the same version of environment `E`.
+    pub fn check_env<E: Environment>(&self) -> Result<()> {
+        if self.env_name != E::NAME {
+            bail!(
+                "The model was trained on `{}`, not `{}`.",
+                self.env_name,
+                E::NAME
+            );
+        }
+        if self.env_version != E::VERSION {
+            bail!(
+                "The model was trained on version {} of `{}`, but this is version {}.",
+                self.env_version,
+                E::NAME,
+                E::VERSION
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks that the model accepts observations from `obs_space` and picks actions
+    /// from `act_space`.
+    pub fn check_spaces(&self, obs_space: &ObsSpace, act_space: &ActSpace) -> Result<()> {
+        let shape = obs_space.shape();
+        let compatible = match self.architecture {
+            // The convolutions and pooling work on any grid size.
+            Architecture::QNet(_) => {
+                shape.len() == self.obs_shape.len() && shape.first() == self.obs_shape.first()
+            }
+            Architecture::Mlp(_) => shape == self.obs_shape,
+        };
+        if !compatible {
+            bail!(
+                "The model takes observations of shape {:?}, but the environment produces {shape:?}.",
+                self.obs_shape
+            );
+        }
+        if act_space.size() != self.action_count {
+            bail!(
+                "The model picks from {} actions, but the environment has {}.",
+                self.action_count,
+                act_space.size()
+            );
+        }
+        Ok(())
+    }
+
+    /// Creates the network described, with weights from `vs`.
+    pub fn build(&self, vs: VarBuilder) -> Result<Box<dyn QModel>> {
+        self.architecture
+            .build(vs, &self.obs_shape, self.action_count, &self.distribution)
+            .context("The model's weights don't match its architecture.")
+    }
+}
+
+/// Writes `tensors` and `metadata` to a safetensors file.
+///
+/// Tensors are converted to `f32`. The bytes are written by hand because candle uses a
+/// different version of the `safetensors` crate.
+pub(crate) fn save_tensors(
+    tensors: Vec<(String, Tensor)>,
+    metadata: HashMap<String, String>,
+    path: &Path,
+) -> Result<()> {
+    let data = tensors
+        .into_iter()
+        .map(|(name, t)| {
+            let shape = t.dims().to_vec();
+            let bytes: Vec<u8> = t
+                .to_dtype(DType::F32)?
+                .flatten_all()?
+                .to_vec1::<f32>()?
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect();
+            Ok((name, shape, bytes))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let views = data
+        .iter()
+        .map(|(name, shape, bytes)| {
+            Ok((
+                name.as_str(),
+                TensorView::new(Dtype::F32, shape.clone(), bytes)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    safetensors::serialize_to_file(views, &Some(metadata), path)?;
+    Ok(())
+}
+
+/// Saves the variables of a network along with metadata describing it.
+pub fn save_model(vm: &VarMap, meta: &ModelMeta, path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    let tensors = vm
+        .data()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, var)| (name.clone(), var.as_tensor().clone()))
+        .collect();
+    save_tensors(tensors, meta.to_metadata()?, path)
+        .with_context(|| format!("Could not save model to {}", path.display()))
+}
+
+/// Reads the metadata and weights of a model saved by `save_model`.
+///
+/// Files without metadata were saved before it was added, and are read as the grid
+/// `QNet` of that time.
+pub fn read_model(data: &[u8]) -> Result<(ModelMeta, VarBuilder<'static>)> {
+    let (_, header) = SafeTensors::read_metadata(data).context("Invalid safetensors file.")?;
+    let meta = match header.metadata() {
+        Some(metadata) => ModelMeta::from_metadata(metadata)?,
+        None => ModelMeta::legacy(),
+    };
+    let tensors = candle_core::safetensors::load_buffer(data, &Device::Cpu)?;
+    Ok((
+        meta,
+        VarBuilder::from_tensors(tensors, DType::F32, &Device::Cpu),
+    ))
+}
+
+/// Loads a model to act in `env`, rejecting models trained for anything else.
+pub fn load_model<E: Environment>(
+    path: impl AsRef<Path>,
+    env: &E,
+) -> Result<(Box<dyn QModel>, ModelMeta)> {
+    let path = path.as_ref();
+    let load = || -> Result<_> {
+        let data = std::fs::read(path)?;
+        let (meta, vs) = read_model(&data)?;
+        meta.check_env::<E>()?;
+        meta.check_spaces(&env.observation_space(), &env.action_space())?;
+        Ok((meta.build(vs)?, meta))
+    };
+    load().with_context(|| format!("Could not load model {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_nn::Init;
+
+    use super::*;
+    use crate::{cartpole::CartpoleEnv, env::GridEnv, model::MlpConfig};
+
+    fn cartpole_meta() -> ModelMeta {
+        ModelMeta::new(
+            &CartpoleEnv::with_seed(Some(0)),
+            Architecture::Mlp(MlpConfig::default()),
+            ValueDistribution::C51 {
+                atoms: 11,
+                v_min: 0.,
+                v_max: 10.,
+            },
+            30,
+            "0123456789abcdef".into(),
+        )
+    }
+
+    fn weights() -> VarMap {
+        let vm = VarMap::new();
+        vm.get((2, 3), "w", Init::Const(0.5), DType::F32, &Device::Cpu)
+            .unwrap();
+        vm
+    }
+
+    fn read_w(vs: &VarBuilder) -> Vec<Vec<f32>> {
+        vs.get((2, 3), "w").unwrap().to_vec2().unwrap()
+    }
+
+    #[test]
+    fn save_and_read_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.safetensors");
+        let meta = cartpole_meta();
+        save_model(&weights(), &meta, &path).unwrap();
+
+        let (read, vs) = read_model(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(read, meta);
+        assert_eq!(read_w(&vs), vec![vec![0.5; 3]; 2]);
+    }
+
+    #[test]
+    fn models_without_metadata_are_legacy_grid_nets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.safetensors");
+        let tensors = HashMap::from([(
+            "w".to_string(),
+            Tensor::ones((2, 3), DType::F32, &Device::Cpu).unwrap(),
+        )]);
+        candle_core::safetensors::save(&tensors, &path).unwrap();
+
+        let (meta, vs) = read_model(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(meta, ModelMeta::legacy());
+        assert_eq!(read_w(&vs), vec![vec![1.; 3]; 2]);
+
+        // Models saved before distributions were added predict expected values.
+        let mut metadata = cartpole_meta().to_metadata().unwrap();
+        metadata.remove("distribution");
+        save_tensors(Vec::new(), metadata, &path).unwrap();
+        let (meta, _) = read_model(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(meta.distribution, ValueDistribution::Expected);
+    }
+
+    #[test]
+    fn check_env_rejects_other_environments() {
+        let meta = cartpole_meta();
+        meta.check_env::<CartpoleEnv>().unwrap();
+        assert!(meta.check_env::<GridEnv>().is_err());
+        let old = ModelMeta {
+            env_version: CartpoleEnv::VERSION + 1,
+            ..meta
+        };
+        assert!(old.check_env::<CartpoleEnv>().is_err());
+    }
+
+    #[test]
+    fn check_spaces_rejects_other_shapes() {
+        let box_space = |size| ObsSpace::Box {
+            low: vec![0.; size],
+            high: vec![1.; size],
+        };
+        let meta = cartpole_meta();
+        meta.check_spaces(&box_space(4), &ActSpace::Discrete(2))
+            .unwrap();
+        assert!(meta
+            .check_spaces(&box_space(5), &ActSpace::Discrete(2))
+            .is_err());
+        assert!(meta
+            .check_spaces(&box_space(4), &ActSpace::Discrete(3))
+            .is_err());
+
+        // Grid networks take any grid size, but not other channels.
+        let grid = ModelMeta::legacy();
+        let planes = |channels, size| ObsSpace::MultiBinary(vec![channels, size, size]);
+        grid.check_spaces(&planes(NUM_CHANNELS, 12), &ActSpace::Discrete(4))
+            .unwrap();
+        assert!(grid
+            .check_spaces(
+                &planes(NUM_CHANNELS + 1, DEFAULT_GRID_SIZE),
+                &ActSpace::Discrete(4)
+            )
+            .is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::Mutex;
+
+use candle_core::{DType, Device, Result, Shape, Tensor, Var};
+use candle_nn::{
+    init::{Init, NormalOrUniform},
+    var_builder::SimpleBackend,
+    VarBuilder, VarMap,
+};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_distr::StandardNormal;
+
+/// Returns an RNG for one consumer of randomness in a run.
+/// Each `stream` produces an independent sequence for the same `seed`.
+pub fn seeded_rng(seed: u64, stream: u64) -> ChaCha8Rng {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    rng.set_stream(stream);
+    rng
+}
+
+/// Derives a seed for components that take a `u64`, like environments.
+pub fn derive_seed(seed: u64, stream: u64) -> u64 {
+    seeded_rng(seed, stream).gen()
+}
+
+/// Creates variables in a `VarMap` like `VarBuilder::from_varmap`, but draws initial
+/// values from a seeded RNG instead of candle's, which can't be seeded on the CPU.
+/// Variables are initialized in the order the network requests them.
+pub struct SeededVarMap {
+    vm: VarMap,
+    rng: Mutex<ChaCha8Rng>,
+}
+
+impl SeededVarMap {
+    pub fn new(vm: &VarMap, rng: ChaCha8Rng) -> Self {
+        Self {
+            vm: vm.clone(),
+            rng: Mutex::new(rng),
+        }
+    }
+
+    pub fn var_builder(self, dtype: DType, device: &Device) -> VarBuilder<'static> {
+        VarBuilder::from_backend(Box::new(self), dtype, device.clone())
+    }
+
+    /// Samples initial values, following `Init::var`.
+    fn init(&self, shape: &Shape, init: Init) -> Vec<f32> {
+        let mut rng = self.rng.lock().unwrap();
+        let count = shape.elem_count();
+        let uniform = |rng: &mut ChaCha8Rng, lo: f64, up: f64| -> Vec<f32> {
+            (0..count).map(|_| rng.gen_range(lo..up) as f32).collect()
+        };
+        let normal = |rng: &mut ChaCha8Rng, mean: f64, stdev: f64| -> Vec<f32> {
+            (0..count)
+                .map(|_| (mean + stdev * rng.sample::<f64, _>(StandardNormal)) as f32)
+                .collect()
+        };
+        match init {
+            Init::Const(v) => vec![v as f32; count],
+            Init::Uniform { lo, up } => uniform(&mut rng, lo, up),
+            Init::Randn { mean, stdev } => normal(&mut rng, mean, stdev),
+            Init::Kaiming {
+                dist,
+                fan,
+                non_linearity,
+            } => {
+                let std = non_linearity.gain() / (fan.for_shape(shape) as f64).sqrt();
+                match dist {
+                    NormalOrUniform::Uniform => {
+                        let bound = 3f64.sqrt() * std;
+                        uniform(&mut rng, -bound, bound)
+                    }
+                    NormalOrUniform::Normal => normal(&mut rng, 0., std),
+                }
+            }
+        }
+    }
+}
+
+impl SimpleBackend for SeededVarMap {
+    fn get(&self, s: Shape, name: &str, h: Init, dtype: DType, dev: &Device) -> Result<Tensor> {
+        if let Some(var) = self.vm.data().lock().unwrap().get(name) {
+            let var_shape = var.shape();
+            if &s != var_shape {
+                candle_core::bail!("shape mismatch on {name}: {s:?} <> {var_shape:?}")
+            }
+            return Ok(var.as_tensor().clone());
+        }
+        let values = Tensor::from_vec(self.init(&s, h), &s, dev)?.to_dtype(dtype)?;
+        let var = Var::from_tensor(&values)?;
+        let tensor = var.as_tensor().clone();
+        self.vm.data().lock().unwrap().insert(name.to_string(), var);
+        Ok(tensor)
+    }
+
+    fn get_unchecked(&self, _name: &str, _dtype: DType, _dev: &Device) -> Result<Tensor> {
+        candle_core::bail!("`get_unchecked` does not make sense for `SeededVarMap`, use `get`.");
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.vm.data().lock().unwrap().contains_key(name)
+    }
+}
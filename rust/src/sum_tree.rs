@@ -0,0 +1,125 @@
+/// A binary tree where each node holds the sum of its children.
+/// Used for sampling proportional to priority, with O(log n) updates and lookups.
+pub struct SumTree {
+    pub capacity: usize,
+    /// Number of leaves, rounded up to a power of two.
+    leaf_count: usize,
+    /// Node 1 is the root, children of `i` are `2i` and `2i + 1`.
+    nodes: Vec<f32>,
+}
+
+impl SumTree {
+    pub fn new(capacity: usize) -> Self {
+        let leaf_count = capacity.next_power_of_two();
+        Self {
+            capacity,
+            leaf_count,
+            nodes: vec![0.; leaf_count * 2],
+        }
+    }
+
+    /// Returns the sum of all values.
+    pub fn total(&self) -> f32 {
+        self.nodes[1]
+    }
+
+    pub fn get(&self, index: usize) -> f32 {
+        self.nodes[self.leaf_count + index]
+    }
+
+    /// Returns the values of all leaves.
+    pub fn values(&self) -> &[f32] {
+        &self.nodes[self.leaf_count..self.leaf_count + self.capacity]
+    }
+
+    pub fn set(&mut self, index: usize, value: f32) {
+        assert!(index < self.capacity, "Index {index} is out of bounds.");
+        let mut node = self.leaf_count + index;
+        self.nodes[node] = value;
+        while node > 1 {
+            node /= 2;
+            self.nodes[node] = self.nodes[2 * node] + self.nodes[2 * node + 1];
+        }
+    }
+
+    /// Returns the index of the first leaf where the running sum exceeds `value`.
+    pub fn find(&self, mut value: f32) -> usize {
+        let mut node = 1;
+        while node < self.leaf_count {
+            let left = self.nodes[2 * node];
+            let right = self.nodes[2 * node + 1];
+            // Also go left if rounding error pushed us past a subtree with nothing in it.
+            if value < left || right <= 0. {
+                node *= 2;
+            } else {
+                value -= left;
+                node = 2 * node + 1;
+            }
+        }
+        (node - self.leaf_count).min(self.capacity - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn total_tracks_updates() {
+        let mut tree = SumTree::new(4);
+        assert_eq!(tree.total(), 0.);
+        tree.set(0, 1.);
+        tree.set(3, 2.5);
+        assert_eq!(tree.total(), 3.5);
+        tree.set(0, 0.5);
+        assert_eq!(tree.total(), 3.);
+        assert_eq!(tree.get(3), 2.5);
+        assert_eq!(tree.values(), &[0.5, 0., 0., 2.5]);
+    }
+
+    #[test]
+    fn find_returns_leaf_covering_value() {
+        let mut tree = SumTree::new(4);
+        for (i, value) in [1., 2., 0., 3.].into_iter().enumerate() {
+            tree.set(i, value);
+        }
+        assert_eq!(tree.find(0.), 0);
+        assert_eq!(tree.find(0.99), 0);
+        assert_eq!(tree.find(1.), 1);
+        assert_eq!(tree.find(2.99), 1);
+        // Leaves holding 0 are never picked.
+        assert_eq!(tree.find(3.), 3);
+        assert_eq!(tree.find(5.99), 3);
+    }
+
+    #[test]
+    fn non_power_of_two_capacity() {
+        let mut tree = SumTree::new(5);
+        for i in 0..5 {
+            tree.set(i, 1.);
+        }
+        assert_eq!(tree.total(), 5.);
+        assert_eq!(tree.values().len(), 5);
+        for i in 0..5 {
+            assert_eq!(tree.find(i as f32 + 0.5), i);
+        }
+    }
+
+    #[test]
+    fn find_past_total_stays_in_bounds() {
+        let mut tree = SumTree::new(5);
+        for i in 0..5 {
+            tree.set(i, 1.);
+        }
+        // Rounding can push the value past the total, which would walk into the
+        // padding leaves.
+        assert_eq!(tree.find(5.), 4);
+        assert_eq!(tree.find(100.), 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn set_past_capacity_panics() {
+        SumTree::new(5).set(5, 1.);
+    }
+}
@@ -0,0 +1,412 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+
+use crate::environment::RgbImage;
+
+/// Number of buckets histograms are split into.
+const HISTOGRAM_BUCKETS: usize = 30;
+
+/// Writes TensorBoard event files without depending on TensorFlow.
+///
+/// Each event is a protobuf `Event` message, framed as a TFRecord: the length, a masked
+/// CRC32C of the length, the data and a masked CRC32C of the data.
+pub struct SummaryWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl SummaryWriter {
+    /// Creates a new event file in `dir`, which TensorBoard shows as one run.
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Could not create log directory {}", dir.display()))?;
+        let host = std::env::var("HOSTNAME").unwrap_or("localhost".into());
+        let path = dir.join(format!("events.out.tfevents.{}.{host}", wall_time() as u64));
+        let file = File::create(&path)
+            .with_context(|| format!("Could not create event file {}", path.display()))?;
+        let mut writer = Self {
+            path,
+            writer: BufWriter::new(file),
+        };
+        let mut event = event_header(0);
+        write_bytes(&mut event, 3, b"brain.Event:2");
+        writer.write_record(&event)?;
+        Ok(writer)
+    }
+
+    /// Marks that the run restarted at `step`. TensorBoard discards events from older
+    /// files with a step at or after this one, e.g. those logged after the checkpoint
+    /// being resumed from.
+    pub fn add_session_start(&mut self, step: usize) -> Result<()> {
+        let mut session_log = Vec::new();
+        // `SessionLog.status`, where 1 is `START`.
+        write_varint_field(&mut session_log, 1, 1);
+        let mut event = event_header(step);
+        // `Event.session_log`.
+        write_bytes(&mut event, 7, &session_log);
+        self.write_record(&event)
+    }
+
+    pub fn add_scalar(&mut self, tag: &str, value: f32, step: usize) -> Result<()> {
+        let mut value_msg = Vec::new();
+        write_bytes(&mut value_msg, 1, tag.as_bytes());
+        write_tag(&mut value_msg, 2, WIRE_FIXED32);
+        value_msg.extend_from_slice(&value.to_le_bytes());
+        self.write_summary(&value_msg, step)
+    }
+
+    /// Adds a histogram of `values`, split into equal width buckets.
+    pub fn add_histogram(&mut self, tag: &str, values: &[f32], step: usize) -> Result<()> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        let values: Vec<_> = values.iter().map(|&v| v as f64).collect();
+        let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let bucket_count = if min == max { 1 } else { HISTOGRAM_BUCKETS };
+        let width = (max - min) / bucket_count as f64;
+        let mut buckets = vec![0.; bucket_count];
+        for v in &values {
+            let i = if width > 0. {
+                (((v - min) / width) as usize).min(bucket_count - 1)
+            } else {
+                0
+            };
+            buckets[i] += 1.;
+        }
+        let limits: Vec<_> = (1..=bucket_count)
+            .map(|i| {
+                if i == bucket_count {
+                    max
+                } else {
+                    min + width * i as f64
+                }
+            })
+            .collect();
+
+        let mut histo = Vec::new();
+        write_double(&mut histo, 1, min);
+        write_double(&mut histo, 2, max);
+        write_double(&mut histo, 3, values.len() as f64);
+        write_double(&mut histo, 4, values.iter().sum());
+        write_double(&mut histo, 5, values.iter().map(|v| v * v).sum());
+        write_packed_doubles(&mut histo, 6, &limits);
+        write_packed_doubles(&mut histo, 7, &buckets);
+
+        let mut value_msg = Vec::new();
+        write_bytes(&mut value_msg, 1, tag.as_bytes());
+        write_bytes(&mut value_msg, 5, &histo);
+        self.write_summary(&value_msg, step)
+    }
+
+    /// Adds an image, stored as a PNG.
+    pub fn add_image(&mut self, tag: &str, image: &RgbImage, step: usize) -> Result<()> {
+        let mut image_msg = Vec::new();
+        write_varint_field(&mut image_msg, 1, image.height as u64);
+        write_varint_field(&mut image_msg, 2, image.width as u64);
+        // Colorspace, 3 is RGB.
+        write_varint_field(&mut image_msg, 3, 3);
+        write_bytes(&mut image_msg, 4, &encode_png(image));
+
+        let mut value_msg = Vec::new();
+        write_bytes(&mut value_msg, 1, tag.as_bytes());
+        write_bytes(&mut value_msg, 4, &image_msg);
+        self.write_summary(&value_msg, step)
+    }
+
+    /// Writes buffered events to disk.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer
+            .flush()
+            .with_context(|| format!("Could not write events to {}", self.path.display()))
+    }
+
+    /// Writes an event with a summary holding a single `Summary.Value`.
+    fn write_summary(&mut self, value_msg: &[u8], step: usize) -> Result<()> {
+        let mut summary = Vec::new();
+        write_bytes(&mut summary, 1, value_msg);
+        let mut event = event_header(step);
+        write_bytes(&mut event, 5, &summary);
+        self.write_record(&event)
+    }
+
+    /// Writes `data` as a TFRecord.
+    fn write_record(&mut self, data: &[u8]) -> Result<()> {
+        let len = (data.len() as u64).to_le_bytes();
+        let mut record = Vec::with_capacity(data.len() + 16);
+        record.extend_from_slice(&len);
+        record.extend_from_slice(&masked_crc32c(&len).to_le_bytes());
+        record.extend_from_slice(data);
+        record.extend_from_slice(&masked_crc32c(data).to_le_bytes());
+        self.writer
+            .write_all(&record)
+            .with_context(|| format!("Could not write events to {}", self.path.display()))
+    }
+}
+
+fn wall_time() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.)
+}
+
+/// Starts an `Event` message with the wall time and step set.
+fn event_header(step: usize) -> Vec<u8> {
+    let mut event = Vec::new();
+    write_double(&mut event, 1, wall_time());
+    write_varint_field(&mut event, 2, step as u64);
+    event
+}
+
+// Protobuf encoding.
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_BYTES: u8 = 2;
+const WIRE_FIXED32: u8 = 5;
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_tag(out: &mut Vec<u8>, field: u32, wire_type: u8) {
+    write_varint(out, ((field as u64) << 3) | wire_type as u64);
+}
+
+fn write_varint_field(out: &mut Vec<u8>, field: u32, value: u64) {
+    write_tag(out, field, WIRE_VARINT);
+    write_varint(out, value);
+}
+
+fn write_double(out: &mut Vec<u8>, field: u32, value: f64) {
+    write_tag(out, field, WIRE_FIXED64);
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_bytes(out: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    write_tag(out, field, WIRE_BYTES);
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn write_packed_doubles(out: &mut Vec<u8>, field: u32, values: &[f64]) {
+    let bytes: Vec<_> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+    write_bytes(out, field, &bytes);
+}
+
+// Checksums.
+
+/// CRC-32 used by PNG.
+const CRC32_TABLE: [u32; 256] = crc_table(0xedb8_8320);
+/// CRC-32C (Castagnoli) used by TFRecord.
+const CRC32C_TABLE: [u32; 256] = crc_table(0x82f6_3b78);
+
+/// Builds the lookup table for the reflected polynomial `poly`.
+const fn crc_table(poly: u32) -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            c = if c & 1 == 1 { poly ^ (c >> 1) } else { c >> 1 };
+            bit += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+/// Table driven CRC-32.
+fn crc32(table: &[u32; 256], data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| {
+        table[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// CRC32C, masked as TFRecord expects so CRCs of data containing CRCs stay robust.
+fn masked_crc32c(data: &[u8]) -> u32 {
+    let crc = crc32(&CRC32C_TABLE, data);
+    crc.rotate_right(15).wrapping_add(0xa282_ead8)
+}
+
+// PNG encoding.
+
+/// Encodes an image as a PNG, using uncompressed deflate blocks.
+fn encode_png(image: &RgbImage) -> Vec<u8> {
+    // Each row starts with a filter type byte, 0 for none.
+    let row_len = image.width * 3;
+    let mut raw = Vec::with_capacity((row_len + 1) * image.height);
+    for row in image.pixels.chunks(row_len) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    // Zlib stream with stored blocks, followed by the Adler-32 of the raw data.
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<_> = raw.chunks(u16::MAX as usize).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        zlib.push((i == blocks.len() - 1) as u8);
+        let len = block.len() as u16;
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    let (a, b) = raw.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    zlib.extend_from_slice(&((b << 16) | a).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&(image.width as u32).to_be_bytes());
+    header.extend_from_slice(&(image.height as u32).to_be_bytes());
+    // 8 bits per channel, RGB, default compression, filtering and no interlacing.
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+    for (kind, data) in [(b"IHDR", &header), (b"IDAT", &zlib), (b"IEND", &Vec::new())] {
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = png.len();
+        png.extend_from_slice(kind);
+        png.extend_from_slice(data);
+        let crc = crc32(&CRC32_TABLE, &png[start..]);
+        png.extend_from_slice(&crc.to_be_bytes());
+    }
+    png
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc_check_values() {
+        assert_eq!(crc32(&CRC32C_TABLE, b"123456789"), 0xe306_9283);
+        assert_eq!(crc32(&CRC32_TABLE, b"123456789"), 0xcbf4_3926);
+        assert_eq!(masked_crc32c(b"123456789"), 0xc78a_b0e5);
+    }
+
+    #[test]
+    fn protobuf_encoding() {
+        // Examples from the protobuf encoding guide.
+        let mut out = Vec::new();
+        write_varint(&mut out, 300);
+        assert_eq!(out, [0xac, 0x02]);
+
+        let mut out = Vec::new();
+        write_varint_field(&mut out, 1, 150);
+        assert_eq!(out, [0x08, 0x96, 0x01]);
+
+        let mut out = Vec::new();
+        write_bytes(&mut out, 2, b"testing");
+        assert_eq!(out, [0x12, 0x07, b't', b'e', b's', b't', b'i', b'n', b'g']);
+
+        let mut out = Vec::new();
+        write_double(&mut out, 1, 1.);
+        assert_eq!(out, [0x09, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f]);
+    }
+
+    /// Reads a varint from the start of `data`, advancing past it.
+    fn read_varint(data: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..).step_by(7) {
+            let (&byte, rest) = data.split_first().unwrap();
+            *data = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                break;
+            }
+        }
+        value
+    }
+
+    /// Splits a protobuf message into its field numbers and raw values.
+    fn decode_fields(mut data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut fields = Vec::new();
+        while !data.is_empty() {
+            let tag = read_varint(&mut data);
+            let len = match tag as u8 & 7 {
+                WIRE_VARINT => {
+                    let start = data;
+                    read_varint(&mut data);
+                    fields.push((
+                        (tag >> 3) as u32,
+                        start[..start.len() - data.len()].to_vec(),
+                    ));
+                    continue;
+                }
+                WIRE_FIXED64 => 8,
+                WIRE_FIXED32 => 4,
+                WIRE_BYTES => read_varint(&mut data) as usize,
+                wire_type => panic!("unexpected wire type {wire_type}"),
+            };
+            let (value, rest) = data.split_at(len);
+            fields.push(((tag >> 3) as u32, value.to_vec()));
+            data = rest;
+        }
+        fields
+    }
+
+    /// Reads every record of the writer's event file, checking their framing.
+    fn read_records(writer: &SummaryWriter) -> Vec<Vec<u8>> {
+        let data = std::fs::read(&writer.path).unwrap();
+        let mut records = Vec::new();
+        let mut rest = data.as_slice();
+        while !rest.is_empty() {
+            let (len_bytes, tail) = rest.split_at(8);
+            let len = u64::from_le_bytes(len_bytes.try_into().unwrap()) as usize;
+            let len_crc = u32::from_le_bytes(tail[..4].try_into().unwrap());
+            assert_eq!(len_crc, masked_crc32c(len_bytes));
+            let record = &tail[4..4 + len];
+            let crc = u32::from_le_bytes(tail[4 + len..8 + len].try_into().unwrap());
+            assert_eq!(crc, masked_crc32c(record));
+            records.push(record.to_vec());
+            rest = &tail[8 + len..];
+        }
+        records
+    }
+
+    #[test]
+    fn records_are_framed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = SummaryWriter::new(dir.path()).unwrap();
+        writer.add_scalar("loss", 0.5, 3).unwrap();
+        writer.flush().unwrap();
+
+        let records = read_records(&writer);
+        // The file version event, then the scalar.
+        assert_eq!(records.len(), 2);
+        assert!(records[1].windows(4).any(|w| w == b"loss"));
+    }
+
+    #[test]
+    fn session_start_is_a_session_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = SummaryWriter::new(dir.path()).unwrap();
+        writer.add_session_start(300).unwrap();
+        writer.flush().unwrap();
+
+        let records = read_records(&writer);
+        let fields = decode_fields(&records[1]);
+        let numbers: Vec<_> = fields.iter().map(|(field, _)| *field).collect();
+        // Wall time, step and `session_log`.
+        assert_eq!(numbers, [1, 2, 7]);
+        assert_eq!(read_varint(&mut fields[1].1.as_slice()), 300);
+        // `status` is `START`.
+        assert_eq!(decode_fields(&fields[2].1), [(1, vec![1])]);
+    }
+}
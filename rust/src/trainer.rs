@@ -0,0 +1,337 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use indicatif::{ProgressBar, ProgressIterator, ProgressStyle};
+use rand::{seq::SliceRandom, Rng};
+
+use crate::{
+    checkpoint::Checkpoint,
+    config::TrainConfig,
+    dqn::train_dqn,
+    environment::{EnvState, Environment, ObsSpace},
+    evaluate::{evaluate, mask_to_tensor, process_obs, GreedyPolicy, INFINITY},
+    metrics::{IterationMetrics, MetricsLogger},
+    model::QModel,
+    optim::AdamW,
+    replay_buffer::ReplayBuffer,
+    saved_model::{save_model, ModelMeta},
+    seeding::{derive_seed, seeded_rng, SeededVarMap},
+    tensorboard::SummaryWriter,
+};
+
+// RNG streams derived from the run's seed.
+const TRAIN_ENV_STREAM: u64 = 0;
+const TEST_ENV_STREAM: u64 = 1;
+const Q_NET_STREAM: u64 = 2;
+const TARGET_NET_STREAM: u64 = 3;
+const TRAIN_STREAM: u64 = 4;
+
+/// Returns where checkpoints of the run called `name` are saved.
+pub fn checkpoint_dir(out_dir: impl AsRef<Path>, name: &str) -> PathBuf {
+    out_dir.as_ref().join(format!("checkpoint_{name}"))
+}
+
+/// Trains a Q network on environments created by `new_env`.
+/// Outputs are saved in `out_dir` with `name` in their file names, and the config they
+/// were trained with is saved next to them. Models carry a `ModelMeta` describing the
+/// network and environment, so `load_model` can rebuild them.
+///
+/// Noisy networks get new noise for every step and training iteration, and are
+/// evaluated with their mean weights.
+///
+/// All randomness is derived from `config.seed`. Environments are created with their
+/// own seed, and networks must draw their initial weights from the `VarBuilder` given.
+///
+/// If `resume` is set, training continues from the last checkpoint, in the same
+/// episodes and with the same randomness as if it had never stopped.
+///
+/// Per iteration metrics are written to `metrics_{name}.<format>` in `out_dir`, and
+/// TensorBoard events to `runs/{name}` if enabled.
+///
+/// Returns the total Q loss of every iteration that trained.
+pub fn train<E: Environment + EnvState, M: QModel>(
+    new_env: impl Fn(u64) -> Result<E>,
+    new_net: impl Fn(VarBuilder, &ObsSpace, usize) -> Result<M>,
+    name: &str,
+    config: &TrainConfig,
+    resume: bool,
+    out_dir: impl AsRef<Path>,
+) -> Result<Vec<f32>> {
+    let device = Device::Cpu;
+    let out_dir = out_dir.as_ref();
+    std::fs::create_dir_all(out_dir)
+        .with_context(|| format!("Could not create output directory {}", out_dir.display()))?;
+    let mut train_env = new_env(derive_seed(config.seed, TRAIN_ENV_STREAM))?;
+    let mut test_env = new_env(derive_seed(config.seed, TEST_ENV_STREAM))?;
+    let obs_space = train_env.observation_space();
+
+    // Initialize Q network
+    let act_space = train_env.action_space().size();
+    let mut vm = VarMap::new();
+    let vs = SeededVarMap::new(&vm, seeded_rng(config.seed, Q_NET_STREAM))
+        .var_builder(DType::F32, &device);
+    let q_net = new_net(vs, &obs_space, act_space)?;
+    let mut target_vm = VarMap::new();
+    let target_vs = SeededVarMap::new(&target_vm, seeded_rng(config.seed, TARGET_NET_STREAM))
+        .var_builder(DType::F32, &device);
+    let q_net_target = new_net(target_vs, &obs_space, act_space)?;
+    let mut q_opt = AdamW::new_lr(&vm, config.q_lr)?;
+    let config_hash = config.hash()?;
+
+    // A replay buffer stores experience collected over all sampling runs
+    let mut buffer = ReplayBuffer::new_prioritized(
+        &obs_space,
+        act_space,
+        config.buffer_size,
+        config.prioritized,
+        config.priority_alpha as f32,
+    )
+    .with_n_step(config.n_step, config.discount as f32);
+
+    // Drives exploration and minibatch sampling.
+    let mut rng = seeded_rng(config.seed, TRAIN_STREAM);
+    let (obs_, info) = train_env.reset()?;
+    let mut obs = process_obs(&obs_)?;
+    let mut mask = mask_to_tensor(&info.action_mask)?;
+    let checkpoint_dir = checkpoint_dir(out_dir, name);
+    let mut start_step = 0;
+    if resume {
+        let mut checkpoint = Checkpoint {
+            step: 0,
+            config,
+            q_vars: &mut vm,
+            target_vars: &mut target_vm,
+            optimizer: &mut q_opt,
+            rng: &mut rng,
+            buffer: &mut buffer,
+            train_env: &mut train_env,
+            test_env: &mut test_env,
+            obs: &mut obs,
+            mask: &mut mask,
+        };
+        checkpoint.load(&checkpoint_dir)?;
+        start_step = checkpoint.step;
+        println!("Resuming from iteration {start_step}.");
+    }
+
+    config.save(out_dir.join(format!("q_net_{name}.toml")))?;
+    let mut losses = Vec::new();
+    let mut metrics = match config.metrics_format.extension() {
+        Some(ext) => {
+            let path = out_dir.join(format!("metrics_{name}.{ext}"));
+            Some(if resume {
+                MetricsLogger::resume(path, config.metrics_format, start_step)?
+            } else {
+                MetricsLogger::create(path, config.metrics_format)?
+            })
+        }
+        None => None,
+    };
+    let mut summary = if config.tensorboard {
+        let mut summary = SummaryWriter::new(out_dir.join("runs").join(name))?;
+        if resume {
+            summary.add_session_start(start_step)?;
+        }
+        Some(summary)
+    } else {
+        None
+    };
+
+    let progress = ProgressBar::new(config.iterations as u64)
+        .with_style(ProgressStyle::with_template(
+            "[{eta_precise}] {wide_bar} {pos:>7}/{len:7}",
+        )?)
+        .with_position(start_step as u64);
+    for step in (start_step..config.iterations).progress_with(progress.clone()) {
+        let percent_done = step as f32 / config.iterations as f32;
+        // Noisy networks explore by themselves once warmed up.
+        let epsilon = if step < config.warmup_steps {
+            1.
+        } else if q_net.is_noisy() {
+            0.
+        } else {
+            config.q_epsilon as f32 * f32::max(1.0 - percent_done, 0.05)
+        };
+
+        // Collect experience
+        for _ in 0..config.train_steps {
+            let action = if rng.gen::<f32>() < epsilon {
+                let indices: Vec<_> = mask
+                    .squeeze(0)?
+                    .to_vec1::<f32>()?
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(a, t)| if *t < 0.5 { Some(a) } else { None })
+                    .collect();
+                *(indices.choose(&mut rng).unwrap()) as u32
+            } else {
+                q_net.resample_noise(&mut rng)?;
+                let q_vals =
+                    (q_net.forward(&obs)?.detach()? * (1. - &mask)? + (&mask * -INFINITY)?)?;
+                q_vals.argmax(1)?.squeeze(0)?.to_scalar::<u32>()?
+            };
+            let (obs_, reward, info) = train_env.step(action);
+            let next_obs = process_obs(&obs_)?;
+            let next_mask = mask_to_tensor(&info.action_mask)?;
+            buffer.insert_step(
+                obs,
+                next_obs.clone(),
+                Tensor::new(&[action], &Device::Cpu)?,
+                &[reward],
+                &[info.terminated],
+                &[info.truncated],
+                next_mask.clone(),
+            )?;
+            obs = next_obs;
+            mask = next_mask;
+            if info.done() {
+                let (obs_, info) = train_env.reset()?;
+                obs = process_obs(&obs_)?;
+                mask = mask_to_tensor(&info.action_mask)?;
+            }
+        }
+
+        // Train
+        let beta = config.start_beta as f32 + percent_done * (1. - config.start_beta as f32);
+        let mut row = IterationMetrics {
+            iteration: step,
+            epsilon,
+            buffer_fill: buffer.len() as f32 / buffer.capacity as f32,
+            ..Default::default()
+        };
+        if buffer.filled {
+            let stats = train_dqn(
+                &q_net,
+                &q_net_target,
+                &mut q_opt,
+                &mut vm,
+                &mut buffer,
+                &device,
+                config.train_iters,
+                config.train_batch_size,
+                beta,
+                config.double_q,
+                &mut rng,
+            )?;
+            losses.push(stats.total_q_loss);
+            row.loss = Some(stats.total_q_loss);
+            row.mean_abs_td = Some(stats.mean_abs_td);
+            row.q_mean = Some(stats.q_mean);
+            row.q_min = Some(stats.q_min);
+            row.q_max = Some(stats.q_max);
+
+            // Evaluate the network's performance after this training iteration.
+            if step % 100 == 0 {
+                q_net.remove_noise();
+                let report = evaluate(
+                    &mut GreedyPolicy::new(&q_net),
+                    &mut test_env,
+                    config.eval_steps,
+                    config.max_eval_steps,
+                )?;
+                let eval_return = report.returns().mean as f32;
+                let eval_length = report.lengths().mean as f32;
+                row.eval_return = Some(eval_return);
+                row.eval_length = Some(eval_length);
+                if let Some(summary) = &mut summary {
+                    summary.add_histogram("q_values", &stats.q_values, step)?;
+                    // Shows where the last evaluation episode ended.
+                    if let Some(image) = test_env.render_image() {
+                        summary.add_image("eval_episode", &image, step)?;
+                    }
+                }
+                progress.println(format!(
+                    "Eval reward: {eval_return}, Total Q Loss: {}",
+                    stats.total_q_loss
+                ));
+            }
+
+            // Update Q target
+            if (step + 1) % config.target_update == 0 {
+                for (name, v) in vm.data().lock().unwrap().iter() {
+                    target_vm.set_one(name, v.as_tensor().clone())?;
+                }
+            }
+
+            // Save network
+            if (step + 1) % 10 == 0 {
+                let meta = ModelMeta::new(
+                    &train_env,
+                    q_net.architecture(),
+                    q_net.distribution().clone(),
+                    step + 1,
+                    config_hash.clone(),
+                );
+                save_model(
+                    &vm,
+                    &meta,
+                    out_dir.join(format!("q_net_{name}.safetensors")),
+                )?;
+            }
+        }
+
+        if let Some(metrics) = &mut metrics {
+            metrics.log(&row)?;
+        }
+        if let Some(summary) = &mut summary {
+            let scalars = [
+                ("loss", row.loss),
+                ("mean_abs_td", row.mean_abs_td),
+                ("q_mean", row.q_mean),
+                ("epsilon", Some(row.epsilon)),
+                ("buffer_fill", Some(row.buffer_fill)),
+                ("eval_return", row.eval_return),
+                ("eval_length", row.eval_length),
+            ];
+            for (tag, value) in scalars {
+                if let Some(value) = value {
+                    summary.add_scalar(tag, value, step)?;
+                }
+            }
+        }
+
+        // Save everything needed to resume training
+        if (step + 1) % config.checkpoint_interval == 0 {
+            if let Some(metrics) = &mut metrics {
+                metrics.flush()?;
+            }
+            if let Some(summary) = &mut summary {
+                summary.flush()?;
+            }
+            Checkpoint {
+                step: step + 1,
+                config,
+                q_vars: &mut vm,
+                target_vars: &mut target_vm,
+                optimizer: &mut q_opt,
+                rng: &mut rng,
+                buffer: &mut buffer,
+                train_env: &mut train_env,
+                test_env: &mut test_env,
+                obs: &mut obs,
+                mask: &mut mask,
+            }
+            .save(&checkpoint_dir)?;
+        }
+    }
+
+    if let Some(metrics) = &mut metrics {
+        metrics.flush()?;
+    }
+    if let Some(summary) = &mut summary {
+        summary.flush()?;
+    }
+
+    // Report final performance.
+    q_net.remove_noise();
+    let report = evaluate(
+        &mut GreedyPolicy::new(&q_net),
+        &mut test_env,
+        config.report_eval_steps,
+        config.max_eval_steps,
+    )?;
+    println!("{report}");
+    Ok(losses)
+}
@@ -0,0 +1,92 @@
+//! Small training runs shared by the integration tests.
+// Each test crate only uses some of these.
+#![allow(dead_code)]
+
+use std::path::Path;
+
+use rust::{
+    cartpole::CartpoleEnv,
+    config::TrainConfig,
+    env::GridEnv,
+    level::{LevelGenConfig, LevelGenerator, LevelSource},
+    model::{MlpQNet, QNet},
+    trainer::train,
+};
+
+/// Small enough that training starts after a few iterations.
+pub fn test_config(seed: u64) -> TrainConfig {
+    TrainConfig {
+        // Covers the randomness of prioritized sampling too.
+        prioritized: true,
+        ..small_config(TrainConfig::default(), seed)
+    }
+}
+
+/// Shrinks the run and buffer of `config` so training starts after a few iterations.
+pub fn small_config(config: TrainConfig, seed: u64) -> TrainConfig {
+    TrainConfig {
+        iterations: 30,
+        train_steps: 8,
+        warmup_steps: 5,
+        buffer_size: 64,
+        train_batch_size: 16,
+        target_update: 10,
+        eval_steps: 1,
+        max_eval_steps: 50,
+        report_eval_steps: 1,
+        checkpoint_interval: 1000,
+        seed,
+        ..config
+    }
+}
+
+/// Trains on cartpole with the network `config` describes and returns the loss of every
+/// iteration that trained.
+pub fn cartpole_losses(config: &TrainConfig, resume: bool, out_dir: &Path) -> Vec<f32> {
+    train(
+        |seed| Ok(CartpoleEnv::with_seed(Some(seed))),
+        |vs, obs_space, act_space| {
+            MlpQNet::with_config(
+                vs,
+                obs_space.size(),
+                act_space,
+                &config.mlp,
+                &config.distribution,
+            )
+        },
+        "cartpole",
+        config,
+        resume,
+        out_dir,
+    )
+    .unwrap()
+}
+
+/// Trains on generated grid levels with the network `config` describes and returns the
+/// loss of every iteration that trained. The convolutional network is slow in debug builds, so keep `config` small.
+pub fn grid_losses(config: &TrainConfig, resume: bool, out_dir: &Path) -> Vec<f32> {
+    train(
+        |seed| {
+            Ok(GridEnv::with_levels(LevelSource::Generator(
+                LevelGenerator::new(LevelGenConfig {
+                    seed: Some(seed),
+                    ..Default::default()
+                })?,
+            )))
+        },
+        |vs, obs_space, act_space| {
+            QNet::with_config(
+                vs,
+                obs_space.shape()[0],
+                act_space,
+                &config.model,
+                &config.distribution,
+            )
+        },
+        "grid",
+        config,
+        resume,
+        out_dir,
+    )
+    .unwrap()
+}
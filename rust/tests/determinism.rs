@@ -0,0 +1,36 @@
+mod common;
+
+use common::{cartpole_losses, grid_losses, test_config};
+use rust::config::TrainConfig;
+
+fn cartpole_run(seed: u64) -> Vec<f32> {
+    let dir = tempfile::tempdir().unwrap();
+    cartpole_losses(&test_config(seed), false, dir.path())
+}
+
+fn grid_run(seed: u64) -> Vec<f32> {
+    let dir = tempfile::tempdir().unwrap();
+    // The convolutional network is slow in debug builds, so only train a few times.
+    let config = TrainConfig {
+        iterations: 12,
+        train_batch_size: 4,
+        ..test_config(seed)
+    };
+    grid_losses(&config, false, dir.path())
+}
+
+#[test]
+fn cartpole_same_seed_same_losses() {
+    let losses = cartpole_run(7);
+    assert!(!losses.is_empty());
+    assert_eq!(losses, cartpole_run(7));
+    assert_ne!(losses, cartpole_run(8));
+}
+
+#[test]
+fn grid_same_seed_same_losses() {
+    let losses = grid_run(7);
+    assert!(!losses.is_empty());
+    assert_eq!(losses, grid_run(7));
+    assert_ne!(losses, grid_run(8));
+}
@@ -0,0 +1,31 @@
+mod common;
+
+use common::{cartpole_losses, grid_losses, small_config};
+use rust::config::TrainConfig;
+
+/// Checks that every Rainbow component trains together without errors.
+fn check_losses(losses: &[f32]) {
+    assert!(!losses.is_empty());
+    assert!(losses.iter().all(|l| l.is_finite()), "{losses:?}");
+}
+
+#[test]
+fn rainbow_trains_on_cartpole() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = small_config(TrainConfig::rainbow(0., 100.), 3);
+    config.validate().unwrap();
+    check_losses(&cartpole_losses(&config, false, dir.path()));
+}
+
+#[test]
+fn rainbow_trains_on_grid() {
+    let dir = tempfile::tempdir().unwrap();
+    // The convolutional network is slow in debug builds, so only train a few times.
+    let config = TrainConfig {
+        iterations: 12,
+        train_batch_size: 4,
+        ..small_config(TrainConfig::rainbow(-2., 2.), 3)
+    };
+    config.validate().unwrap();
+    check_losses(&grid_losses(&config, false, dir.path()));
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use std::path::Path;
+
+use common::{cartpole_losses, grid_losses, test_config};
+use rust::config::TrainConfig;
+
+/// Trains to the end while saving a single checkpoint partway through, then resumes
+/// from that checkpoint. Episodes, n-step windows and evaluations are in progress on
+/// both sides of it. Returns the losses of both runs.
+fn run_and_resume(
+    config: &TrainConfig,
+    run: impl Fn(&TrainConfig, bool, &Path) -> Vec<f32>,
+) -> (Vec<f32>, Vec<f32>) {
+    let dir = tempfile::tempdir().unwrap();
+    let full = run(config, false, dir.path());
+    let resumed = run(config, true, dir.path());
+    (full, resumed)
+}
+
+#[test]
+fn cartpole_resumes_loss_for_loss() {
+    let config = TrainConfig {
+        checkpoint_interval: 20,
+        n_step: 3,
+        ..test_config(11)
+    };
+    let (full, resumed) = run_and_resume(&config, cartpole_losses);
+    assert_eq!(resumed.len(), 10);
+    assert_eq!(resumed, full[full.len() - resumed.len()..]);
+}
+
+#[test]
+fn grid_resumes_loss_for_loss() {
+    let config = TrainConfig {
+        iterations: 12,
+        train_batch_size: 4,
+        checkpoint_interval: 8,
+        n_step: 3,
+        ..test_config(11)
+    };
+    let (full, resumed) = run_and_resume(&config, grid_losses);
+    assert_eq!(resumed.len(), 4);
+    assert_eq!(resumed, full[full.len() - resumed.len()..]);
+}
@@ -5,7 +5,7 @@ export const PIT_ICON = "bi-exclamation-octagon-fill color-danger";
 export const GOAL_ICON = "bi-flag";
 export const WALL_ICON = "bi-square-fill";
 export const BOX_ICON = "bi-box2-fill";
-export const ICONS = [COIN_ICON, PIT_ICON, GOAL_ICON, WALL_ICON, BOX_ICON];
+export const ICONS = [COIN_ICON, PIT_ICON, WALL_ICON, BOX_ICON, GOAL_ICON];
 export const ACTION_ICONS = [
   "bi-arrow-left",
   "bi-arrow-right",
@@ -13,13 +13,13 @@ export const ACTION_ICONS = [
   "bi-arrow-down",
 ];
 
-// Reference grid
+// Cell values returned by `GridEnv.cells`.
 export const EMPTY = 0;
 export const COIN = 1;
 export const PIT = 2;
-export const GOAL = 3;
-export const WALL = 4;
-export const BOX = 5;
+export const WALL = 3;
+export const BOX = 4;
+export const GOAL = 5;
 
 export type Position = [number, number];
 export type GameState = [number[][], Position];
\ No newline at end of file